@@ -10,4 +10,94 @@ pub enum BloomErrorCode {
 
     #[msg("Miscalculation")]
     Miscalculation,
+
+    #[msg("Rebalance In Progress")]
+    RebalanceInProgress,
+
+    #[msg("Invalid Rebalance State")]
+    InvalidRebalanceState,
+
+    #[msg("Slippage Exceeded")]
+    SlippageExceeded,
+
+    #[msg("Invalid Output Mint")]
+    InvalidOutputMint,
+
+    #[msg("Insufficient Pool Tokens")]
+    InsufficientPoolTokens,
+
+    #[msg("Deposit Locked")]
+    DepositLocked,
+
+    #[msg("Invalid Fee")]
+    InvalidFee,
+
+    #[msg("Total Liquidity Cap Exceeded")]
+    TotalLiquidityCapExceeded,
+
+    #[msg("User Shares Cap Exceeded")]
+    UserSharesCapExceeded,
+
+    #[msg("Not Allowlisted")]
+    NotAllowlisted,
+
+    #[msg("Invalid Merkle Proof")]
+    InvalidMerkleProof,
+
+    #[msg("Invalid Config")]
+    InvalidConfig,
+
+    #[msg("Timelock Not Elapsed")]
+    TimelockNotElapsed,
+
+    #[msg("Invalid Vault Manager")]
+    InvalidVaultManager,
+
+    #[msg("Invalid Tick Array Lower")]
+    InvalidTickArrayLower,
+
+    #[msg("Invalid Tick Array Upper")]
+    InvalidTickArrayUpper,
+
+    #[msg("Invalid Tick Array Current")]
+    InvalidTickArrayCurrent,
+
+    #[msg("Invalid Tick Range")]
+    InvalidTickRange,
+
+    #[msg("Invalid Strategy Program")]
+    InvalidStrategyProgram,
+
+    #[msg("Missing Strategy Return Data")]
+    MissingStrategyReturnData,
+
+    #[msg("Strategy Range Not Aligned")]
+    StrategyRangeNotAligned,
+
+    #[msg("Strategy Range Too Narrow")]
+    StrategyRangeTooNarrow,
+
+    #[msg("Strategy Range Too Wide")]
+    StrategyRangeTooWide,
+
+    #[msg("Strategy Range Deviation Exceeded")]
+    StrategyRangeDeviationExceeded,
+
+    #[msg("Invalid Range Order")]
+    InvalidRangeOrder,
+
+    #[msg("Invalid Vault Mode")]
+    InvalidVaultMode,
+
+    #[msg("Invalid Deposit Mode")]
+    InvalidDepositMode,
+
+    #[msg("Invalid Tick Array Swap")]
+    InvalidTickArraySwap,
+
+    #[msg("Insufficient Price Observations")]
+    InsufficientPriceObservations,
+
+    #[msg("Pool Token Amount Overflow")]
+    PoolTokenAmountOverflow,
 }
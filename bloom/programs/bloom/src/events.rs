@@ -0,0 +1,157 @@
+use anchor_lang::prelude::*;
+
+use crate::instructions::{RangeSpec, VaultConfig};
+
+#[event]
+pub struct VaultInitialized {
+    pub vault_manager: Pubkey,
+    pub pool: Pubkey,
+    pub token_a: Pubkey,
+    pub token_b: Pubkey,
+    pub pool_token: Pubkey,
+    pub pool_position: Pubkey,
+    pub admin: Pubkey,
+    pub tick_lower_index: i32,
+    pub tick_upper_index: i32,
+}
+
+#[event]
+pub struct Deposited {
+    pub vault_manager: Pubkey,
+    pub user: Pubkey,
+    pub liquidity: u128,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub pool_token_amount: u64,
+}
+
+#[event]
+pub struct Withdrawn {
+    pub vault_manager: Pubkey,
+    pub user: Pubkey,
+    pub liquidity: u128,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub pool_token_amount: u64,
+}
+
+#[event]
+pub struct FeesCollected {
+    pub vault_manager: Pubkey,
+    pub pool_position: Pubkey,
+    pub fee_a: u64,
+    pub fee_b: u64,
+}
+
+#[event]
+pub struct PositionInRange {
+    pub vault_manager: Pubkey,
+    pub tick_lower_index: i32,
+    pub tick_upper_index: i32,
+    pub tick_current_index: i32,
+    pub sqrt_price_x64: u128,
+}
+
+// emitted by rebalance_positions, and by rebalance_deposit at the end of a split rebalance, once
+// the vault is in its new position. a split rebalance reports its range as the resolved ticks
+#[event]
+pub struct Rebalanced {
+    pub vault_manager: Pubkey,
+    pub old_tick_lower_index: i32,
+    pub old_tick_upper_index: i32,
+    pub new_tick_lower_index: i32,
+    pub new_tick_upper_index: i32,
+    // Q64.64 prices of token_a in token_b at the new ticks
+    pub new_price_lower_x64: u128,
+    pub new_price_upper_x64: u128,
+    pub range_spec: RangeSpec,
+    pub sqrt_price_x64: u128,
+    pub a_to_b: bool,
+    pub swap_amount_in: u64,
+    pub swap_amount_out: u64,
+    pub fee_a: u64,
+    pub fee_b: u64,
+    pub liquidity: u128,
+    pub token_a_leftover: u64,
+    pub token_b_leftover: u64,
+}
+
+// emitted by rebalance_positions alongside Rebalanced when a range order vault harvests a fill,
+// sell_a is the side of the order that was filled
+#[event]
+pub struct RangeOrderFilled {
+    pub vault_manager: Pubkey,
+    pub sell_a: bool,
+    pub filled_tick_lower_index: i32,
+    pub filled_tick_upper_index: i32,
+    pub next_tick_lower_index: i32,
+    pub next_tick_upper_index: i32,
+    pub liquidity: u128,
+}
+
+// the multi-transaction rebalance emits one event per step, fees are reported by FeesCollected
+#[event]
+pub struct RebalanceWithdrawn {
+    pub vault_manager: Pubkey,
+    pub old_tick_lower_index: i32,
+    pub old_tick_upper_index: i32,
+    pub new_tick_lower_index: i32,
+    pub new_tick_upper_index: i32,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+}
+
+// min_amount_out is the bound taken from the time weighted price, swap_cost is what the swap
+// lost against the price before it, in the output token
+#[event]
+pub struct RebalanceSwapped {
+    pub vault_manager: Pubkey,
+    pub sqrt_price_before_x64: u128,
+    pub sqrt_price_x64: u128,
+    pub a_to_b: bool,
+    pub swap_amount_in: u64,
+    pub swap_amount_out: u64,
+    pub min_amount_out: u64,
+    pub swap_cost: u64,
+}
+
+// the admin moved the target of a rebalance that the price left before rebalance_deposit
+#[event]
+pub struct RebalanceRetargeted {
+    pub vault_manager: Pubkey,
+    pub old_tick_lower_index: i32,
+    pub old_tick_upper_index: i32,
+    pub new_tick_lower_index: i32,
+    pub new_tick_upper_index: i32,
+    pub range_spec: RangeSpec,
+}
+
+#[event]
+pub struct RebalanceDeposited {
+    pub vault_manager: Pubkey,
+    pub new_tick_lower_index: i32,
+    pub new_tick_upper_index: i32,
+    pub sqrt_price_x64: u128,
+    pub liquidity: u128,
+    pub token_a_leftover: u64,
+    pub token_b_leftover: u64,
+}
+
+#[event]
+pub struct ConfigChangeQueued {
+    pub vault_manager: Pubkey,
+    pub config: VaultConfig,
+    pub eta: i64,
+}
+
+#[event]
+pub struct ConfigChangeExecuted {
+    pub vault_manager: Pubkey,
+    pub config: VaultConfig,
+}
+
+#[event]
+pub struct ConfigChangeCancelled {
+    pub vault_manager: Pubkey,
+    pub config: VaultConfig,
+}
@@ -11,6 +11,7 @@ use whirlpools::state::{TickArray, Whirlpool};
 
 use super::*;
 use crate::errors::BloomErrorCode;
+use crate::events::Deposited;
 use crate::math;
 
 #[derive(Accounts)]
@@ -23,9 +24,13 @@ pub struct AddLiquidity<'info> {
         has_one = token_a,
         has_one = token_b,
         has_one = token_a_pool_vault,
-        has_one = token_b_pool_vault)]
+        has_one = token_b_pool_vault,
+        constraint = !vault_manager.rebalance_state.in_progress() @ BloomErrorCode::RebalanceInProgress)]
     pub vault_manager: Box<Account<'info, VaultManager>>,
 
+    #[account(mut, seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
     pub token_a: Box<Account<'info, token::Mint>>,
 
     pub token_b: Box<Account<'info, token::Mint>>,
@@ -46,7 +51,7 @@ pub struct AddLiquidity<'info> {
     #[account(mut, seeds = [b"position", pool_position_mint.key().as_ref()], bump, seeds::program = whirlpool_program)]
     pub pool_position: UncheckedAccount<'info>,
 
-    #[account(seeds = [b"pool_position_mint", vault_manager.pool_position_mint_seed.as_bytes(), pool.key().as_ref()], bump)]
+    #[account(address = vault_manager.pool_position_mint)]
     pub pool_position_mint: Box<Account<'info, token::Mint>>,
 
     #[account(token::mint = pool_position_mint, token::authority = vault_manager)]
@@ -70,6 +75,9 @@ pub struct AddLiquidity<'info> {
     #[account(init_if_needed, payer = user, associated_token::mint = pool_token, associated_token::authority = user)]
     pub user_pool_token_ata: Account<'info, token::TokenAccount>,
 
+    #[account(init_if_needed, payer = user, space = UserPosition::space(), seeds = [b"user_position", vault_manager.key().as_ref(), user.key().as_ref()], bump)]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
     pub token_program: Program<'info, token::Token>,
     pub whirlpool_program: Program<'info, WhirlpoolProgram>,
     pub system_program: Program<'info, System>,
@@ -77,7 +85,203 @@ pub struct AddLiquidity<'info> {
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
 }
 
-pub fn handler(ctx: Context<AddLiquidity>, token_a_amount_in: u64) -> Result<()> {
+// deposit ledger of a user in a vault. shares only counts pool tokens minted to the user by a
+// deposit, pool tokens received from another wallet stay untracked and pay the early exit fee
+// without being locked. it is required rather than optional on deposits and withdrawals, a
+// withdraw that could leave it out would also skip the early exit fee, so the user pays rent
+// for it on first use
+#[account]
+pub struct UserPosition {
+    pub vault_manager: Pubkey,
+    pub user: Pubkey,
+    pub token_a_deposited: u64,
+    pub token_b_deposited: u64,
+    pub token_a_withdrawn: u64,
+    pub token_b_withdrawn: u64,
+    pub shares: u64,
+    pub first_deposit_timestamp: i64,
+    pub last_deposit_timestamp: i64,
+    // average value in token b paid per pool token for the shares held, x64 fixed point
+    pub entry_share_price_x64: u128,
+}
+
+impl UserPosition {
+    pub fn space() -> usize {
+        8 + 32 + 32 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 16
+    }
+
+    // sets the owner on the first deposit or withdraw, init_if_needed leaves it zeroed
+    pub fn set_owner_if_new(&mut self, vault_manager: Pubkey, user: Pubkey) {
+        if self.vault_manager == Pubkey::default() {
+            self.vault_manager = vault_manager;
+            self.user = user;
+        }
+    }
+
+    pub fn record_deposit(
+        &mut self,
+        now: i64,
+        token_a_amount: u64,
+        token_b_amount: u64,
+        sqrt_price_x64: u128,
+        pool_token_amount: u64,
+    ) {
+        self.entry_share_price_x64 = math::calculate_average_share_price_x64(
+            self.entry_share_price_x64,
+            self.shares,
+            math::calculate_share_price_x64(
+                token_a_amount,
+                token_b_amount,
+                sqrt_price_x64,
+                pool_token_amount,
+            ),
+            pool_token_amount,
+        );
+        self.shares += pool_token_amount;
+        self.token_a_deposited += token_a_amount;
+        self.token_b_deposited += token_b_amount;
+        if self.first_deposit_timestamp == 0 {
+            self.first_deposit_timestamp = now;
+        }
+        self.last_deposit_timestamp = now;
+    }
+
+    // pool tokens in the wallet the ledger has no deposit for, e.g. received from another wallet
+    pub fn untracked_pool_tokens(&self, pool_token_balance: u64) -> u64 {
+        pool_token_balance.saturating_sub(self.shares)
+    }
+
+    // untracked pool tokens are withdrawn first, only the rest comes out of shares
+    pub fn record_withdraw(
+        &mut self,
+        token_a_amount: u64,
+        token_b_amount: u64,
+        pool_token_amount: u64,
+        untracked_pool_tokens: u64,
+    ) {
+        let tracked_pool_tokens =
+            pool_token_amount.saturating_sub(untracked_pool_tokens.min(pool_token_amount));
+        self.shares = self.shares.saturating_sub(tracked_pool_tokens);
+        self.token_a_withdrawn += token_a_amount;
+        self.token_b_withdrawn += token_b_amount;
+    }
+}
+
+// how the size of a deposit is specified, the other amounts are derived from the position and
+// bounded by the user's maximums
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepositMode {
+    ExactA {
+        token_a_amount_in: u64,
+        token_max_b: u64,
+    },
+    ExactB {
+        token_b_amount_in: u64,
+        token_max_a: u64,
+    },
+    ExactLiquidity {
+        liquidity: u128,
+        token_max_a: u64,
+        token_max_b: u64,
+    },
+    ExactShares {
+        pool_token_amount: u64,
+        token_max_a: u64,
+        token_max_b: u64,
+    },
+}
+
+impl DepositMode {
+    // the most of each token the user will pay, an exact token amount needs no maximum of its own
+    pub fn token_maxes(&self) -> (u64, u64) {
+        match *self {
+            DepositMode::ExactA { token_max_b, .. } => (u64::MAX, token_max_b),
+            DepositMode::ExactB { token_max_a, .. } => (token_max_a, u64::MAX),
+            DepositMode::ExactLiquidity {
+                token_max_a,
+                token_max_b,
+                ..
+            }
+            | DepositMode::ExactShares {
+                token_max_a,
+                token_max_b,
+                ..
+            } => (token_max_a, token_max_b),
+        }
+    }
+}
+
+// returns the liquidity to add and the pool tokens to mint for a deposit, a position out of
+// range (a resting range order) only takes the one token it holds so sizing by the other fails
+pub fn calculate_deposit(
+    deposit_mode: DepositMode,
+    sqrt_price_lower_x64: u128,
+    sqrt_price_current_x64: u128,
+    sqrt_price_upper_x64: u128,
+    position_liquidity: u128,
+    pool_token_supply: u64,
+) -> Result<(u128, u64)> {
+    let sqrt_price_current_x64 =
+        sqrt_price_current_x64.clamp(sqrt_price_lower_x64, sqrt_price_upper_x64);
+
+    let liquidity = match deposit_mode {
+        DepositMode::ExactA {
+            token_a_amount_in, ..
+        } => {
+            if sqrt_price_current_x64 >= sqrt_price_upper_x64 {
+                return Err(error!(BloomErrorCode::InvalidDepositMode));
+            }
+            math::get_liquidity_from_token_a(
+                token_a_amount_in as u128,
+                sqrt_price_current_x64,
+                sqrt_price_upper_x64,
+            )
+        }
+        DepositMode::ExactB {
+            token_b_amount_in, ..
+        } => {
+            if sqrt_price_current_x64 <= sqrt_price_lower_x64 {
+                return Err(error!(BloomErrorCode::InvalidDepositMode));
+            }
+            math::get_liquidity_from_token_b(
+                token_b_amount_in as u128,
+                sqrt_price_lower_x64,
+                sqrt_price_current_x64,
+            )
+        }
+        DepositMode::ExactLiquidity { liquidity, .. } => liquidity,
+        DepositMode::ExactShares {
+            pool_token_amount, ..
+        } => {
+            let liquidity = math::calculate_liquidity_for_pool_tokens(
+                pool_token_amount,
+                pool_token_supply,
+                position_liquidity,
+                true,
+            );
+            return Ok((liquidity, pool_token_amount));
+        }
+    };
+
+    let pool_token_mint_to_amount = math::calculate_pool_tokens_for_liquidity(
+        liquidity,
+        position_liquidity,
+        pool_token_supply,
+        false,
+    )
+    .ok_or_else(|| error!(BloomErrorCode::PoolTokenAmountOverflow))?;
+
+    Ok((liquidity, pool_token_mint_to_amount))
+}
+
+pub fn handler(ctx: Context<AddLiquidity>, deposit_mode: DepositMode) -> Result<()> {
+    ctx.accounts.vault_manager.check_access(
+        ctx.accounts.vault_manager.key(),
+        ctx.accounts.user.key(),
+        ctx.remaining_accounts,
+        ctx.program_id,
+    )?;
+
     // Have to do this hacky thing because of anchor-lang version mismatch
     let pool_position =
         &mut WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
@@ -86,19 +290,41 @@ pub fn handler(ctx: Context<AddLiquidity>, token_a_amount_in: u64) -> Result<()>
     let tick_index_upper = pool_position.tick_upper_index;
     let tick_index_current = ctx.accounts.pool.tick_current_index;
 
-    // check that our position is within range of the pool
-    if tick_index_current < tick_index_lower || tick_index_current >= tick_index_upper {
+    check_tick_array(
+        &ctx.accounts.tick_array_lower.to_account_info(),
+        &ctx.accounts.pool,
+        tick_index_lower,
+        BloomErrorCode::InvalidTickArrayLower,
+    )?;
+    check_tick_array(
+        &ctx.accounts.tick_array_upper.to_account_info(),
+        &ctx.accounts.pool,
+        tick_index_upper,
+        BloomErrorCode::InvalidTickArrayUpper,
+    )?;
+
+    // check that our position is within range of the pool, range orders also take deposits
+    // while they wait to be filled
+    if !ctx.accounts.vault_manager.mode.is_range_order()
+        && (tick_index_current < tick_index_lower || tick_index_current >= tick_index_upper)
+    {
         return Err(error!(BloomErrorCode::PositionOutOfRange));
     }
 
     let sqrt_price_current_x64 = ctx.accounts.pool.sqrt_price;
+    let sqrt_price_lower_x64 = sqrt_price_from_tick_index(tick_index_lower);
     let sqrt_price_upper_x64 = sqrt_price_from_tick_index(tick_index_upper);
 
-    let liquidity = math::get_liquidity_from_token_a(
-        token_a_amount_in as u128,
+    let (liquidity, pool_token_mint_to_amount) = calculate_deposit(
+        deposit_mode,
+        sqrt_price_lower_x64,
         sqrt_price_current_x64,
         sqrt_price_upper_x64,
-    );
+        pool_position.liquidity,
+        ctx.accounts.pool_token.supply,
+    )?;
+
+    // maximums for the approvals and increase_liquidity, whirlpool rounds these up
     let (token_max_a, token_max_b) = calculate_liquidity_token_deltas(
         tick_index_current,
         sqrt_price_current_x64,
@@ -108,18 +334,30 @@ pub fn handler(ctx: Context<AddLiquidity>, token_a_amount_in: u64) -> Result<()>
     .unwrap();
 
     msg!(
-        "liquidity: {}, token_a_amount_in: {}, token_max_a: {}, token_max_b: {}",
+        "deposit_mode: {:?}, liquidity: {}, token_max_a: {}, token_max_b: {}, pool_token_mint_to_amount: {}",
+        deposit_mode,
         liquidity,
-        token_a_amount_in,
         token_max_a,
-        token_max_b
+        token_max_b,
+        pool_token_mint_to_amount
     );
 
-    let pool_token_mint_to_amount = math::calculate_pool_token_mint_to_amount(
-        liquidity,
-        pool_position.liquidity,
-        ctx.accounts.pool_token.supply,
-    );
+    // the price can move between quoting and landing the deposit
+    let (user_max_a, user_max_b) = deposit_mode.token_maxes();
+    if token_max_a > user_max_a || token_max_b > user_max_b {
+        return Err(error!(BloomErrorCode::SlippageExceeded));
+    }
+
+    // shares stay counted after the pool tokens are moved to another wallet
+    let user_shares = ctx
+        .accounts
+        .user_position
+        .shares
+        .max(ctx.accounts.user_pool_token_ata.amount);
+    ctx.accounts.vault_manager.check_deposit_caps(
+        pool_position.liquidity + liquidity,
+        user_shares + pool_token_mint_to_amount,
+    )?;
 
     // TODO: should I be calling revoke at the end?
 
@@ -167,6 +405,9 @@ pub fn handler(ctx: Context<AddLiquidity>, token_a_amount_in: u64) -> Result<()>
         token_program: ctx.accounts.token_program.to_account_info(),
     };
 
+    let token_a_before = ctx.accounts.user_token_a_ata.amount;
+    let token_b_before = ctx.accounts.user_token_b_ata.amount;
+
     increase_liquidity(
         CpiContext::new_with_signer(
             ctx.accounts.whirlpool_program.to_account_info(),
@@ -181,6 +422,12 @@ pub fn handler(ctx: Context<AddLiquidity>, token_a_amount_in: u64) -> Result<()>
         token_max_b,
     )?;
 
+    // whirlpool takes at most the maximums, the ledger records what actually moved
+    ctx.accounts.user_token_a_ata.reload()?;
+    ctx.accounts.user_token_b_ata.reload()?;
+    let token_a_amount = token_a_before - ctx.accounts.user_token_a_ata.amount;
+    let token_b_amount = token_b_before - ctx.accounts.user_token_b_ata.amount;
+
     // mint pool tokens representing the user's share in the position
     let mint_to_accounts = token::MintTo {
         mint: ctx.accounts.pool_token.to_account_info(),
@@ -198,5 +445,41 @@ pub fn handler(ctx: Context<AddLiquidity>, token_a_amount_in: u64) -> Result<()>
             ]],
         ),
         pool_token_mint_to_amount,
-    )
+    )?;
+
+    let now = Clock::get()?.unix_timestamp;
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.update_time(
+        now,
+        position_in_range(tick_index_current, tick_index_lower, tick_index_upper),
+    );
+    vault_stats.update_share_price(
+        pool_position.liquidity + liquidity,
+        tick_index_lower,
+        tick_index_upper,
+        sqrt_price_current_x64,
+        ctx.accounts.pool_token.supply + pool_token_mint_to_amount,
+    );
+    vault_stats.last_deposit_timestamp = now;
+
+    let user_position = &mut ctx.accounts.user_position;
+    user_position.set_owner_if_new(ctx.accounts.vault_manager.key(), ctx.accounts.user.key());
+    user_position.record_deposit(
+        now,
+        token_a_amount,
+        token_b_amount,
+        sqrt_price_current_x64,
+        pool_token_mint_to_amount,
+    );
+
+    emit!(Deposited {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        user: ctx.accounts.user.key(),
+        liquidity,
+        token_a_amount,
+        token_b_amount,
+        pool_token_amount: pool_token_mint_to_amount,
+    });
+
+    Ok(())
 }
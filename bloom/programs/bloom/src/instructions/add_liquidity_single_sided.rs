@@ -0,0 +1,476 @@
+use anchor_lang::prelude::*;
+use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
+use anchor_spl::{associated_token, token};
+use whirlpool::{
+    manager::liquidity_manager::calculate_liquidity_token_deltas,
+    math::{sqrt_price_from_tick_index, MAX_SQRT_PRICE_X64, MIN_SQRT_PRICE_X64},
+    state::Position as WhirlpoolPosition,
+};
+use whirlpools::cpi::{
+    accounts::{IncreaseLiquidity, Swap},
+    {increase_liquidity, swap},
+};
+use whirlpools::program::Whirlpool as WhirlpoolProgram;
+use whirlpools::state::{TickArray, Whirlpool};
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::Deposited;
+use crate::math;
+
+#[derive(Accounts)]
+pub struct AddLiquiditySingleSided<'info> {
+    #[account(seeds = [pool.key().as_ref()], bump,
+        has_one = pool_position,
+        has_one = pool_position_token_account,
+        has_one = pool,
+        has_one = pool_token,
+        has_one = token_a,
+        has_one = token_b,
+        has_one = token_a_vault,
+        has_one = token_b_vault,
+        has_one = token_a_pool_vault,
+        has_one = token_b_pool_vault,
+        constraint = !vault_manager.rebalance_state.in_progress() @ BloomErrorCode::RebalanceInProgress)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(mut, seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
+    pub token_a: Box<Account<'info, token::Mint>>,
+
+    pub token_b: Box<Account<'info, token::Mint>>,
+
+    #[account(mut, seeds = [b"pool_token", vault_manager.key().as_ref()], bump)]
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_a.key().as_ref()], bump, token::mint = token_a, token::authority = vault_manager)]
+    pub token_a_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_b.key().as_ref()], bump, token::mint = token_b, token::authority = vault_manager)]
+    pub token_b_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    #[account(mut, token::mint = token_a)]
+    pub token_a_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, token::mint = token_b)]
+    pub token_b_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    /// CHECK: deserialized inside instruction
+    #[account(mut, seeds = [b"position", pool_position_mint.key().as_ref()], bump, seeds::program = whirlpool_program)]
+    pub pool_position: UncheckedAccount<'info>,
+
+    #[account(address = vault_manager.pool_position_mint)]
+    pub pool_position_mint: Box<Account<'info, token::Mint>>,
+
+    #[account(token::mint = pool_position_mint, token::authority = vault_manager)]
+    pub pool_position_token_account: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub tick_array_lower: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_upper: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_current: AccountLoader<'info, TickArray>,
+
+    // the next two tick arrays in the direction of the swap
+    #[account(mut)]
+    pub tick_array_1: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_2: AccountLoader<'info, TickArray>,
+
+    /// CHECK: Must be provided for swapping but not currently used by Whirlpool Program
+    #[account(seeds = [b"oracle", pool.key().as_ref()], bump, seeds::program = whirlpool_program)]
+    pub oracle: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(init_if_needed, payer = user, associated_token::mint = token_a, associated_token::authority = user)]
+    pub user_token_a_ata: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(init_if_needed, payer = user, associated_token::mint = token_b, associated_token::authority = user)]
+    pub user_token_b_ata: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(init_if_needed, payer = user, associated_token::mint = pool_token, associated_token::authority = user)]
+    pub user_pool_token_ata: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(init_if_needed, payer = user, space = UserPosition::space(), seeds = [b"user_position", vault_manager.key().as_ref(), user.key().as_ref()], bump)]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    pub token_program: Program<'info, token::Token>,
+    pub whirlpool_program: Program<'info, WhirlpoolProgram>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+    pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
+}
+
+// deposit a single token, swapping part of it through the vault's own pool into the deposit ratio
+pub fn handler(
+    ctx: Context<AddLiquiditySingleSided>,
+    amount_in: u64,
+    input_is_token_a: bool,
+    min_pool_token_amount: u64,
+) -> Result<()> {
+    ctx.accounts.vault_manager.check_access(
+        ctx.accounts.vault_manager.key(),
+        ctx.accounts.user.key(),
+        ctx.remaining_accounts,
+        ctx.program_id,
+    )?;
+
+    // Have to do this hacky thing because of anchor-lang version mismatch
+    let pool_position =
+        &mut WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
+
+    let tick_index_lower = pool_position.tick_lower_index;
+    let tick_index_upper = pool_position.tick_upper_index;
+
+    check_tick_array(
+        &ctx.accounts.tick_array_lower.to_account_info(),
+        &ctx.accounts.pool,
+        tick_index_lower,
+        BloomErrorCode::InvalidTickArrayLower,
+    )?;
+    check_tick_array(
+        &ctx.accounts.tick_array_upper.to_account_info(),
+        &ctx.accounts.pool,
+        tick_index_upper,
+        BloomErrorCode::InvalidTickArrayUpper,
+    )?;
+    check_swap_tick_arrays(
+        [
+            &ctx.accounts.tick_array_current.to_account_info(),
+            &ctx.accounts.tick_array_1.to_account_info(),
+            &ctx.accounts.tick_array_2.to_account_info(),
+        ],
+        &ctx.accounts.pool,
+        input_is_token_a,
+    )?;
+
+    // check that our position is within range of the pool, a range order waiting to be filled
+    // only holds one token and takes it without a swap
+    let range_order_resting = ctx.accounts.vault_manager.mode.is_range_order()
+        && !position_in_range(
+            ctx.accounts.pool.tick_current_index,
+            tick_index_lower,
+            tick_index_upper,
+        );
+
+    if range_order_resting {
+        if input_is_token_a != (ctx.accounts.pool.tick_current_index < tick_index_lower) {
+            return Err(error!(BloomErrorCode::InvalidRangeOrder));
+        }
+    } else if !position_in_range(
+        ctx.accounts.pool.tick_current_index,
+        tick_index_lower,
+        tick_index_upper,
+    ) {
+        return Err(error!(BloomErrorCode::PositionOutOfRange));
+    }
+
+    // idle vault balances belong to the vault, only the user's tokens are deposited
+    let token_a_vault_before = ctx.accounts.token_a_vault.amount;
+    let token_b_vault_before = ctx.accounts.token_b_vault.amount;
+
+    // move the user's tokens into the vault so the swap can reuse the vault authority
+    let (user_token_ata, token_vault) = if input_is_token_a {
+        (
+            ctx.accounts.user_token_a_ata.to_account_info(),
+            ctx.accounts.token_a_vault.to_account_info(),
+        )
+    } else {
+        (
+            ctx.accounts.user_token_b_ata.to_account_info(),
+            ctx.accounts.token_b_vault.to_account_info(),
+        )
+    };
+
+    let transfer_in_accounts = token::Transfer {
+        from: user_token_ata,
+        to: token_vault,
+        authority: ctx.accounts.user.to_account_info(),
+    };
+
+    token::transfer(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            transfer_in_accounts,
+        ),
+        amount_in,
+    )?;
+
+    if !range_order_resting {
+        let lower_price = math::sqrt_price_x64_to_price(
+            sqrt_price_from_tick_index(tick_index_lower),
+            ctx.accounts.token_a.decimals,
+            ctx.accounts.token_b.decimals,
+        );
+        let upper_price = math::sqrt_price_x64_to_price(
+            sqrt_price_from_tick_index(tick_index_upper),
+            ctx.accounts.token_a.decimals,
+            ctx.accounts.token_b.decimals,
+        );
+        let current_price = math::sqrt_price_x64_to_price(
+            ctx.accounts.pool.sqrt_price,
+            ctx.accounts.token_a.decimals,
+            ctx.accounts.token_b.decimals,
+        );
+
+        let swap_amount = math::calculate_single_sided_swap_amount(
+            amount_in,
+            input_is_token_a,
+            lower_price,
+            current_price,
+            upper_price,
+        );
+        msg!("amount_in: {}, swap_amount: {}", amount_in, swap_amount);
+
+        let a_to_b = input_is_token_a;
+        let sqrt_price_limit = if a_to_b {
+            MIN_SQRT_PRICE_X64
+        } else {
+            MAX_SQRT_PRICE_X64
+        };
+
+        let swap_accounts = Swap {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            token_authority: ctx.accounts.vault_manager.to_account_info(),
+            whirlpool: ctx.accounts.pool.to_account_info(),
+            token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
+            token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
+            token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
+            token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
+            tick_array0: ctx.accounts.tick_array_current.to_account_info(),
+            tick_array1: ctx.accounts.tick_array_1.to_account_info(),
+            tick_array2: ctx.accounts.tick_array_2.to_account_info(),
+            oracle: ctx.accounts.oracle.to_account_info(),
+        };
+
+        swap(
+            CpiContext::new_with_signer(
+                ctx.accounts.whirlpool_program.to_account_info(),
+                swap_accounts,
+                &[&[
+                    ctx.accounts.pool.key().as_ref(),
+                    &[*ctx.bumps.get("vault_manager").unwrap()],
+                ]],
+            ),
+            swap_amount,
+            0,
+            sqrt_price_limit,
+            true,
+            a_to_b,
+        )?;
+    }
+
+    ctx.accounts.token_a_vault.reload()?;
+    ctx.accounts.token_b_vault.reload()?;
+    ctx.accounts.pool.reload()?;
+
+    let token_a_amount = ctx.accounts.token_a_vault.amount - token_a_vault_before;
+    let token_b_amount = ctx.accounts.token_b_vault.amount - token_b_vault_before;
+
+    // the swap can push the price out of our range
+    let tick_index_current = ctx.accounts.pool.tick_current_index;
+    if !range_order_resting
+        && !position_in_range(tick_index_current, tick_index_lower, tick_index_upper)
+    {
+        return Err(error!(BloomErrorCode::PositionOutOfRange));
+    }
+
+    let sqrt_price_current_x64 = ctx.accounts.pool.sqrt_price;
+    let liquidity = math::get_liquidity_for_amounts(
+        token_a_amount as u128,
+        token_b_amount as u128,
+        sqrt_price_from_tick_index(tick_index_lower),
+        sqrt_price_current_x64,
+        sqrt_price_from_tick_index(tick_index_upper),
+    );
+
+    let (token_max_a, token_max_b) = calculate_liquidity_token_deltas(
+        tick_index_current,
+        sqrt_price_current_x64,
+        &pool_position,
+        liquidity as i128,
+    )
+    .unwrap();
+
+    let pool_token_mint_to_amount = math::calculate_pool_tokens_for_liquidity(
+        liquidity,
+        pool_position.liquidity,
+        ctx.accounts.pool_token.supply,
+        false,
+    )
+    .ok_or_else(|| error!(BloomErrorCode::PoolTokenAmountOverflow))?;
+
+    msg!(
+        "liquidity: {}, token_max_a: {}, token_max_b: {}, pool_token_mint_to_amount: {}",
+        liquidity,
+        token_max_a,
+        token_max_b,
+        pool_token_mint_to_amount
+    );
+
+    if pool_token_mint_to_amount < min_pool_token_amount {
+        return Err(error!(BloomErrorCode::SlippageExceeded));
+    }
+
+    // shares stay counted after the pool tokens are moved to another wallet
+    let user_shares = ctx
+        .accounts
+        .user_position
+        .shares
+        .max(ctx.accounts.user_pool_token_ata.amount);
+    ctx.accounts.vault_manager.check_deposit_caps(
+        pool_position.liquidity + liquidity,
+        user_shares + pool_token_mint_to_amount,
+    )?;
+
+    let increase_liquidity_accounts = IncreaseLiquidity {
+        whirlpool: ctx.accounts.pool.to_account_info(),
+        position: pool_position.to_account_info(),
+        position_authority: ctx.accounts.vault_manager.to_account_info(),
+        position_token_account: ctx.accounts.pool_position_token_account.to_account_info(),
+        tick_array_lower: ctx.accounts.tick_array_lower.to_account_info(),
+        tick_array_upper: ctx.accounts.tick_array_upper.to_account_info(),
+        token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
+        token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
+        token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
+        token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
+        token_program: ctx.accounts.token_program.to_account_info(),
+    };
+
+    let token_a_vault_before_deposit = ctx.accounts.token_a_vault.amount;
+    let token_b_vault_before_deposit = ctx.accounts.token_b_vault.amount;
+
+    increase_liquidity(
+        CpiContext::new_with_signer(
+            ctx.accounts.whirlpool_program.to_account_info(),
+            increase_liquidity_accounts,
+            &[&[
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("vault_manager").unwrap()],
+            ]],
+        ),
+        liquidity,
+        token_max_a,
+        token_max_b,
+    )?;
+
+    // whirlpool takes at most the maximums, the ledger records what actually moved
+    ctx.accounts.token_a_vault.reload()?;
+    ctx.accounts.token_b_vault.reload()?;
+    let token_a_deposited = token_a_vault_before_deposit - ctx.accounts.token_a_vault.amount;
+    let token_b_deposited = token_b_vault_before_deposit - ctx.accounts.token_b_vault.amount;
+
+    // mint pool tokens representing the user's share in the position
+    let mint_to_accounts = token::MintTo {
+        mint: ctx.accounts.pool_token.to_account_info(),
+        to: ctx.accounts.user_pool_token_ata.to_account_info(),
+        authority: ctx.accounts.vault_manager.to_account_info(),
+    };
+
+    token::mint_to(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            mint_to_accounts,
+            &[&[
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("vault_manager").unwrap()],
+            ]],
+        ),
+        pool_token_mint_to_amount,
+    )?;
+
+    let now = Clock::get()?.unix_timestamp;
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.update_time(
+        now,
+        position_in_range(tick_index_current, tick_index_lower, tick_index_upper),
+    );
+    vault_stats.update_share_price(
+        pool_position.liquidity + liquidity,
+        tick_index_lower,
+        tick_index_upper,
+        sqrt_price_current_x64,
+        ctx.accounts.pool_token.supply + pool_token_mint_to_amount,
+    );
+    vault_stats.last_deposit_timestamp = now;
+
+    let user_position = &mut ctx.accounts.user_position;
+    user_position.set_owner_if_new(ctx.accounts.vault_manager.key(), ctx.accounts.user.key());
+    user_position.record_deposit(
+        now,
+        token_a_deposited,
+        token_b_deposited,
+        sqrt_price_current_x64,
+        pool_token_mint_to_amount,
+    );
+
+    emit!(Deposited {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        user: ctx.accounts.user.key(),
+        liquidity,
+        token_a_amount: token_a_deposited,
+        token_b_amount: token_b_deposited,
+        pool_token_amount: pool_token_mint_to_amount,
+    });
+
+    // refund whatever of the user's tokens did not fit the deposit ratio
+    let token_a_refund = ctx.accounts.token_a_vault.amount - token_a_vault_before;
+    let token_b_refund = ctx.accounts.token_b_vault.amount - token_b_vault_before;
+    msg!(
+        "token_a_refund: {}, token_b_refund: {}",
+        token_a_refund,
+        token_b_refund
+    );
+
+    if token_a_refund > 0 {
+        let refund_a_accounts = token::Transfer {
+            from: ctx.accounts.token_a_vault.to_account_info(),
+            to: ctx.accounts.user_token_a_ata.to_account_info(),
+            authority: ctx.accounts.vault_manager.to_account_info(),
+        };
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                refund_a_accounts,
+                &[&[
+                    ctx.accounts.pool.key().as_ref(),
+                    &[*ctx.bumps.get("vault_manager").unwrap()],
+                ]],
+            ),
+            token_a_refund,
+        )?;
+    }
+
+    if token_b_refund > 0 {
+        let refund_b_accounts = token::Transfer {
+            from: ctx.accounts.token_b_vault.to_account_info(),
+            to: ctx.accounts.user_token_b_ata.to_account_info(),
+            authority: ctx.accounts.vault_manager.to_account_info(),
+        };
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                refund_b_accounts,
+                &[&[
+                    ctx.accounts.pool.key().as_ref(),
+                    &[*ctx.bumps.get("vault_manager").unwrap()],
+                ]],
+            ),
+            token_b_refund,
+        )?;
+    }
+
+    Ok(())
+}
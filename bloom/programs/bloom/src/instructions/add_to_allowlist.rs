@@ -0,0 +1,41 @@
+use anchor_lang::prelude::*;
+
+use super::*;
+
+#[derive(Accounts)]
+#[instruction(user: Pubkey)]
+pub struct AddToAllowlist<'info> {
+    #[account(has_one = admin)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(init, payer = admin, space = AllowlistEntry::space(), seeds = [b"allowlist", vault_manager.key().as_ref(), user.as_ref()], bump)]
+    pub allowlist_entry: Account<'info, AllowlistEntry>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// permission for a user to deposit into a vault that is not AccessMode::Open
+#[account]
+pub struct AllowlistEntry {
+    pub vault_manager: Pubkey,
+    pub user: Pubkey,
+    // root the user proved membership against, zeroed for entries added by the admin
+    pub merkle_root: [u8; 32],
+}
+
+impl AllowlistEntry {
+    pub fn space() -> usize {
+        8 + 32 + 32 + 32
+    }
+}
+
+pub fn handler(ctx: Context<AddToAllowlist>, user: Pubkey) -> Result<()> {
+    let allowlist_entry = &mut ctx.accounts.allowlist_entry;
+    allowlist_entry.vault_manager = ctx.accounts.vault_manager.key();
+    allowlist_entry.user = user;
+
+    Ok(())
+}
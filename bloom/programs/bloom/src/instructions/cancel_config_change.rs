@@ -0,0 +1,26 @@
+use anchor_lang::prelude::*;
+
+use super::*;
+use crate::events::ConfigChangeCancelled;
+
+#[derive(Accounts)]
+pub struct CancelConfigChange<'info> {
+    #[account(has_one = admin)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(mut, close = admin, has_one = vault_manager,
+        seeds = [b"pending_config", vault_manager.key().as_ref()], bump)]
+    pub pending_config: Box<Account<'info, PendingConfig>>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+pub fn handler(ctx: Context<CancelConfigChange>) -> Result<()> {
+    emit!(ConfigChangeCancelled {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        config: ctx.accounts.pending_config.config,
+    });
+
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::ConfigChangeExecuted;
+
+#[derive(Accounts)]
+pub struct ExecuteConfigChange<'info> {
+    #[account(mut, has_one = admin, has_one = pool_token)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(mut, close = admin, has_one = vault_manager,
+        seeds = [b"pending_config", vault_manager.key().as_ref()], bump)]
+    pub pending_config: Box<Account<'info, PendingConfig>>,
+
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+pub fn handler(ctx: Context<ExecuteConfigChange>) -> Result<()> {
+    // an empty vault has no depositors to give notice to
+    if ctx.accounts.pool_token.supply > 0
+        && Clock::get()?.unix_timestamp < ctx.accounts.pending_config.eta
+    {
+        return Err(error!(BloomErrorCode::TimelockNotElapsed));
+    }
+
+    let config = ctx.accounts.pending_config.config;
+    ctx.accounts.vault_manager.set_config(config);
+
+    emit!(ConfigChangeExecuted {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        config,
+    });
+
+    Ok(())
+}
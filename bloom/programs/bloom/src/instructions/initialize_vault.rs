@@ -5,8 +5,12 @@ use whirlpools::program::Whirlpool as WhirlpoolProgram;
 use whirlpools::state::Whirlpool;
 use whirlpools::OpenPositionBumps;
 
+use crate::errors::BloomErrorCode;
+use crate::events::VaultInitialized;
+use crate::math;
+use crate::strategy::Strategy;
+
 #[derive(Accounts)]
-#[instruction(pool_position_mint_seed: String)]
 pub struct InitializeVault<'info> {
     #[account(init, payer = admin, space = VaultManager::space(), seeds = [pool.key().as_ref()], bump)]
     pub vault_manager: Box<Account<'info, VaultManager>>,
@@ -15,6 +19,15 @@ pub struct InitializeVault<'info> {
 
     pub token_b: Box<Account<'info, token::Mint>>,
 
+    #[account(init, payer = admin, space = VaultStats::space(), seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
+    #[account(init, payer = admin, space = 8 + std::mem::size_of::<RebalanceHistory>(), seeds = [b"rebalance_history", vault_manager.key().as_ref()], bump)]
+    pub rebalance_history: AccountLoader<'info, RebalanceHistory>,
+
+    #[account(init, payer = admin, space = 8 + std::mem::size_of::<PriceObservations>(), seeds = [b"price_observations", vault_manager.key().as_ref()], bump)]
+    pub price_observations: AccountLoader<'info, PriceObservations>,
+
     #[account(init, payer = admin, seeds = [b"pool_token", vault_manager.key().as_ref()], bump, mint::decimals = 9, mint::authority = vault_manager)]
     pub pool_token: Box<Account<'info, token::Mint>>,
 
@@ -31,7 +44,7 @@ pub struct InitializeVault<'info> {
     pub pool_position: UncheckedAccount<'info>,
 
     /// CHECK: initialized by the Whirlpool Program
-    #[account(mut, seeds = [b"pool_position_mint", pool_position_mint_seed.as_bytes(), pool.key().as_ref()], bump)]
+    #[account(mut, seeds = [b"pool_position_mint", 0u64.to_le_bytes().as_ref(), pool.key().as_ref()], bump)]
     pub pool_position_mint: UncheckedAccount<'info>,
 
     /// CHECK: initialized by the Whirlpool Program
@@ -54,6 +67,11 @@ pub struct InitializeVault<'info> {
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
 }
 
+pub const DEFAULT_CONFIG_TIMELOCK: i64 = 2 * 24 * 60 * 60;
+
+// bump when fields are added and handle the upgrade in migrate_vault
+pub const VAULT_MANAGER_VERSION: u8 = 5;
+
 #[account]
 pub struct VaultManager {
     pub token_a: Pubkey,
@@ -66,23 +84,526 @@ pub struct VaultManager {
     pub token_b_pool_vault: Pubkey,
     pub pool_position: Pubkey,
     pub pool_position_mint: Pubkey,
+    // replaced by position_nonce, kept so the layout of existing accounts does not change
     pub pool_position_mint_seed: String,
     pub pool_position_token_account: Pubkey,
     pub admin: Pubkey,
+    pub rebalance_state: RebalanceState,
+    pub rebalance_lower_tick_index: i32,
+    pub rebalance_upper_tick_index: i32,
+    // seconds after a user's last deposit before they can withdraw without the early exit fee
+    pub min_deposit_duration: i64,
+    // 0 blocks early withdrawals outright
+    pub early_exit_fee_bps: u16,
+    // deposit caps for soft launches, 0 is uncapped
+    pub max_total_liquidity: u128,
+    // checked against the depositing wallet's UserPosition shares, a soft cap that does not stop
+    // one user depositing from several wallets
+    pub max_per_user_shares: u64,
+    pub access_mode: AccessMode,
+    // only used by AccessMode::MerkleRoot
+    pub merkle_root: [u8; 32],
+    // notice depositors get before a queued config change can be executed
+    pub config_timelock: i64,
+    // 0 for vaults created before the account was versioned
+    pub version: u8,
+    // position mints are seeded with [b"pool_position_mint", nonce, pool], this is the current one
+    pub position_nonce: u64,
+    pub strategy: Strategy,
+    // set by initialize_vault, only the side of a range order changes afterwards
+    pub mode: VaultMode,
+    // fees rebalance_withdraw collected, reported by the Rebalanced event of rebalance_deposit
+    pub rebalance_fee_a: u64,
+    pub rebalance_fee_b: u64,
+    // new fields go here and take their space from _reserved, so the account size stays the same
+    pub _reserved: [u64; 6],
 }
 
 impl VaultManager {
     pub fn space() -> usize {
-        8 + 32 + 32 + 32 + 32 + 32 + 32 + 32 + 32 + 32 + 32 + (4 + 6) + 32 + 32
+        8 + 32
+            + 32
+            + 32
+            + 32
+            + 32
+            + 32
+            + 32
+            + 32
+            + 32
+            + 32
+            + (4 + 6)
+            + 32
+            + 32
+            + 1
+            + 4
+            + 4
+            + 8
+            + 2
+            + 16
+            + 8
+            + 1
+            + 32
+            + 8
+            + 1
+            + 8
+            + Strategy::space()
+            + VaultMode::space()
+            + 8 * 8
+    }
+
+    // nonce for the position mint opened by the next rebalance
+    pub fn next_position_nonce(&self) -> u64 {
+        self.position_nonce + 1
+    }
+
+    // everything queue_config_change can update, see VaultConfig
+    pub fn config(&self) -> VaultConfig {
+        VaultConfig {
+            min_deposit_duration: self.min_deposit_duration,
+            early_exit_fee_bps: self.early_exit_fee_bps,
+            max_total_liquidity: self.max_total_liquidity,
+            max_per_user_shares: self.max_per_user_shares,
+            access_mode: self.access_mode,
+            merkle_root: self.merkle_root,
+            config_timelock: self.config_timelock,
+            strategy: self.strategy,
+        }
+    }
+
+    pub fn set_config(&mut self, config: VaultConfig) {
+        self.min_deposit_duration = config.min_deposit_duration;
+        self.early_exit_fee_bps = config.early_exit_fee_bps;
+        self.max_total_liquidity = config.max_total_liquidity;
+        self.max_per_user_shares = config.max_per_user_shares;
+        self.access_mode = config.access_mode;
+        self.merkle_root = config.merkle_root;
+        self.config_timelock = config.config_timelock;
+        self.strategy = config.strategy;
+    }
+
+    // deposits into permissioned vaults need the user's AllowlistEntry as the first remaining account
+    pub fn check_access(
+        &self,
+        vault_manager: Pubkey,
+        user: Pubkey,
+        remaining_accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<()> {
+        if self.access_mode == AccessMode::Open {
+            return Ok(());
+        }
+
+        let allowlist_entry_info = remaining_accounts
+            .first()
+            .ok_or_else(|| error!(BloomErrorCode::NotAllowlisted))?;
+
+        let (allowlist_entry_address, _bump) = Pubkey::find_program_address(
+            &[b"allowlist", vault_manager.as_ref(), user.as_ref()],
+            program_id,
+        );
+        if allowlist_entry_info.key() != allowlist_entry_address {
+            return Err(error!(BloomErrorCode::NotAllowlisted));
+        }
+
+        let allowlist_entry = Account::<AllowlistEntry>::try_from(allowlist_entry_info)?;
+
+        // entries proven against an old root stop working once the admin rotates it
+        if self.access_mode == AccessMode::MerkleRoot
+            && allowlist_entry.merkle_root != self.merkle_root
+        {
+            return Err(error!(BloomErrorCode::NotAllowlisted));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_deposit_caps(
+        &self,
+        position_liquidity_after_deposit: u128,
+        user_shares_after_deposit: u64,
+    ) -> Result<()> {
+        if self.max_total_liquidity != 0
+            && position_liquidity_after_deposit > self.max_total_liquidity
+        {
+            return Err(error!(BloomErrorCode::TotalLiquidityCapExceeded));
+        }
+
+        if self.max_per_user_shares != 0 && user_shares_after_deposit > self.max_per_user_shares {
+            return Err(error!(BloomErrorCode::UserSharesCapExceeded));
+        }
+
+        Ok(())
+    }
+
+    // liquidity left in the position when a user withdraws before min_deposit_duration has passed.
+    // untracked pool tokens have no deposit timestamp, they pay the fee but are never locked
+    pub fn early_exit_fee(
+        &self,
+        liquidity: u128,
+        pool_token_amount: u64,
+        untracked_pool_tokens: u64,
+        now: i64,
+        last_deposit_timestamp: i64,
+    ) -> Result<u128> {
+        if self.min_deposit_duration <= 0 {
+            return Ok(0);
+        }
+
+        let locked = now.saturating_sub(last_deposit_timestamp) < self.min_deposit_duration;
+        math::calculate_withdraw_early_exit_fee(
+            liquidity,
+            pool_token_amount,
+            untracked_pool_tokens,
+            locked,
+            self.early_exit_fee_bps,
+        )
+        .ok_or_else(|| error!(BloomErrorCode::DepositLocked))
+    }
+}
+
+// cumulative performance of a vault, updated by every deposit, withdraw and rebalance
+#[account]
+pub struct VaultStats {
+    pub vault_manager: Pubkey,
+    pub fees_earned_a: u64,
+    pub fees_earned_b: u64,
+    pub rebalance_count: u64,
+    // swap amounts in, by input token
+    pub swap_volume_a: u64,
+    pub swap_volume_b: u64,
+    // see math::calculate_swap_cost, by output token
+    pub swap_cost_a: u64,
+    pub swap_cost_b: u64,
+    pub seconds_in_range: u64,
+    pub seconds_out_of_range: u64,
+    pub in_range: bool,
+    pub share_price_high_water_mark_x64: u128,
+    pub created_at: i64,
+    pub last_update_timestamp: i64,
+    pub last_deposit_timestamp: i64,
+    pub last_withdraw_timestamp: i64,
+    pub last_rebalance_timestamp: i64,
+}
+
+impl VaultStats {
+    pub fn space() -> usize {
+        8 + 32 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 1 + 16 + 8 + 8 + 8 + 8 + 8
+    }
+
+    // credit the time since the last update to whatever range state was seen at that update
+    pub fn update_time(&mut self, now: i64, in_range: bool) {
+        let elapsed = now.saturating_sub(self.last_update_timestamp).max(0) as u64;
+        if self.in_range {
+            self.seconds_in_range += elapsed;
+        } else {
+            self.seconds_out_of_range += elapsed;
+        }
+
+        self.in_range = in_range;
+        self.last_update_timestamp = now;
+    }
+
+    // values the position's tokens at the pool price, see math::calculate_share_price_x64
+    pub fn update_share_price(
+        &mut self,
+        position_liquidity: u128,
+        tick_lower_index: i32,
+        tick_upper_index: i32,
+        sqrt_price_x64: u128,
+        pool_token_supply: u64,
+    ) {
+        let (token_a_amount, token_b_amount) = math::get_amounts_for_liquidity(
+            position_liquidity,
+            math::tick_index_to_sqrt_price_x64(tick_lower_index),
+            sqrt_price_x64,
+            math::tick_index_to_sqrt_price_x64(tick_upper_index),
+        );
+        let share_price_x64 = math::calculate_share_price_x64(
+            token_a_amount,
+            token_b_amount,
+            sqrt_price_x64,
+            pool_token_supply,
+        );
+        if share_price_x64 > self.share_price_high_water_mark_x64 {
+            self.share_price_high_water_mark_x64 = share_price_x64;
+        }
+    }
+
+    pub fn record_swap(&mut self, a_to_b: bool, amount_in: u64, swap_cost: u64) {
+        if a_to_b {
+            self.swap_volume_a += amount_in;
+            self.swap_cost_b += swap_cost;
+        } else {
+            self.swap_volume_b += amount_in;
+            self.swap_cost_a += swap_cost;
+        }
+    }
+}
+
+pub const REBALANCE_HISTORY_LEN: usize = 32;
+
+// the last REBALANCE_HISTORY_LEN rebalances, oldest entry is overwritten first
+#[account(zero_copy)]
+pub struct RebalanceHistory {
+    pub vault_manager: Pubkey,
+    // index the next entry is written to
+    pub head: u64,
+    // total entries ever appended
+    pub count: u64,
+    pub entries: [RebalanceEntry; REBALANCE_HISTORY_LEN],
+}
+
+impl RebalanceHistory {
+    pub fn append(&mut self, entry: RebalanceEntry) {
+        *self.pending_mut() = entry;
+        self.commit();
+    }
+
+    // the split rebalance fills in the entry at head over its three steps, it only becomes
+    // part of the history once rebalance_deposit commits it
+    pub fn pending_mut(&mut self) -> &mut RebalanceEntry {
+        &mut self.entries[self.head as usize]
+    }
+
+    pub fn commit(&mut self) {
+        self.head = (self.head + 1) % REBALANCE_HISTORY_LEN as u64;
+        self.count += 1;
+    }
+}
+
+pub const PRICE_OBSERVATIONS_LEN: usize = 64;
+
+// crank calls closer together than this are not sampled
+pub const MIN_OBSERVATION_INTERVAL: i64 = 60;
+
+// realized volatility is scaled to this horizon, so Strategy::Volatility widths do not depend on
+// how often the crank runs
+pub const VOLATILITY_HORIZON: i64 = PRICE_OBSERVATIONS_LEN as i64 * MIN_OBSERVATION_INTERVAL;
+
+// observations taken before now that the reference price checked against a Strategy::External
+// range needs, so a few manipulated crank samples cannot move it on their own
+pub const MIN_REFERENCE_OBSERVATIONS: usize = 8;
+
+// pool ticks sampled on every crank call, the input of Strategy::Volatility and the time weighted
+// price. observations taken at now are left out of both, so the price a crank sees, and records,
+// never drives its own decision
+#[account(zero_copy)]
+pub struct PriceObservations {
+    pub vault_manager: Pubkey,
+    // index the next observation is written to
+    pub head: u64,
+    // total observations ever recorded
+    pub count: u64,
+    pub observations: [PriceObservation; PRICE_OBSERVATIONS_LEN],
+}
+
+impl PriceObservations {
+    pub fn record(&mut self, timestamp: i64, tick_index: i32) {
+        if self.count > 0 {
+            let last = (self.head as usize + PRICE_OBSERVATIONS_LEN - 1) % PRICE_OBSERVATIONS_LEN;
+            if timestamp - self.observations[last].timestamp < MIN_OBSERVATION_INTERVAL {
+                return;
+            }
+        }
+
+        self.observations[self.head as usize] = PriceObservation {
+            timestamp,
+            tick_index,
+        };
+        self.head = (self.head + 1) % PRICE_OBSERVATIONS_LEN as u64;
+        self.count += 1;
+    }
+
+    // (timestamp, tick_index) recorded before now, oldest first
+    fn observations_before(&self, now: i64) -> Vec<(i64, i32)> {
+        let len = (self.count as usize).min(PRICE_OBSERVATIONS_LEN);
+        let start = (self.head as usize + PRICE_OBSERVATIONS_LEN - len) % PRICE_OBSERVATIONS_LEN;
+
+        (0..len)
+            .map(|i| {
+                let observation = self.observations[(start + i) % PRICE_OBSERVATIONS_LEN];
+                (observation.timestamp, observation.tick_index)
+            })
+            .filter(|(timestamp, _tick_index)| *timestamp < now)
+            .collect()
+    }
+
+    // see math::calculate_realized_volatility_ticks
+    pub fn realized_volatility_ticks(&self, now: i64) -> u64 {
+        math::calculate_realized_volatility_ticks(
+            &self.observations_before(now),
+            VOLATILITY_HORIZON,
+        )
+    }
+
+    // see math::calculate_time_weighted_tick_index, None until some time has passed since the
+    // first observation
+    pub fn time_weighted_tick_index(&self, now: i64) -> Option<i32> {
+        math::calculate_time_weighted_tick_index(&self.observations_before(now), now)
+    }
+
+    // the time weighted price a Strategy::External range is checked against, None until
+    // MIN_REFERENCE_OBSERVATIONS were taken before now, callers fail closed on None
+    pub fn reference_tick_index(&self, now: i64) -> Option<i32> {
+        let observations = self.observations_before(now);
+        if observations.len() < MIN_REFERENCE_OBSERVATIONS {
+            return None;
+        }
+        math::calculate_time_weighted_tick_index(&observations, now)
+    }
+}
+
+#[zero_copy]
+pub struct PriceObservation {
+    pub timestamp: i64,
+    pub tick_index: i32,
+}
+
+#[zero_copy]
+pub struct RebalanceEntry {
+    pub slot: u64,
+    pub old_tick_lower_index: i32,
+    pub old_tick_upper_index: i32,
+    pub new_tick_lower_index: i32,
+    pub new_tick_upper_index: i32,
+    pub sqrt_price_before_x64: u128,
+    pub sqrt_price_after_x64: u128,
+    // 1 when token_a was swapped for token_b
+    pub a_to_b: u8,
+    pub swap_amount_in: u64,
+    pub swap_amount_out: u64,
+    // left in token_a_vault and token_b_vault after the deposit
+    pub token_a_leftover: u64,
+    pub token_b_leftover: u64,
+}
+
+// where a new position goes, offsets are from the pool price when the instruction runs
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeSpec {
+    Ticks {
+        lower_tick_index: i32,
+        upper_tick_index: i32,
+    },
+    BpsOffsets {
+        lower_bps: u16,
+        upper_bps: u16,
+    },
+    // see math::calculate_bps_offsets_from_width
+    WidthAndSkew {
+        width_bps: u16,
+        skew_bps: i16,
+    },
+}
+
+impl RangeSpec {
+    // aligned (lower_tick_index, upper_tick_index) for the pool
+    pub fn to_tick_range(&self, sqrt_price_x64: u128, tick_spacing: u16) -> Result<(i32, i32)> {
+        let tick_range = match *self {
+            RangeSpec::Ticks {
+                lower_tick_index,
+                upper_tick_index,
+            } => math::snap_tick_range(lower_tick_index, upper_tick_index, tick_spacing),
+            RangeSpec::BpsOffsets {
+                lower_bps,
+                upper_bps,
+            } => math::calculate_tick_range_from_bps_offsets(
+                sqrt_price_x64,
+                lower_bps,
+                upper_bps,
+                tick_spacing,
+            ),
+            RangeSpec::WidthAndSkew {
+                width_bps,
+                skew_bps,
+            } => {
+                let (lower_bps, upper_bps) =
+                    math::calculate_bps_offsets_from_width(width_bps, skew_bps);
+                math::calculate_tick_range_from_bps_offsets(
+                    sqrt_price_x64,
+                    lower_bps,
+                    upper_bps,
+                    tick_spacing,
+                )
+            }
+        };
+
+        tick_range.ok_or_else(|| error!(BloomErrorCode::InvalidTickRange))
+    }
+}
+
+// who can deposit, withdrawals are always open so funds can never be trapped
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessMode {
+    Open,
+    // users add themselves with join_allowlist and a proof against merkle_root
+    MerkleRoot,
+    // the admin manages entries with add_to_allowlist and remove_from_allowlist
+    AllowlistPda,
+}
+
+// Liquidity vaults keep a range around the price. RangeOrder vaults keep a one sided position,
+// selling token a above the price or buying with token b below it, and rebalance_positions
+// moves the filled side into an order the other way instead of re-centering
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VaultMode {
+    Liquidity,
+    RangeOrder { sell_a: bool },
+}
+
+impl VaultMode {
+    pub fn space() -> usize {
+        1 + 1
+    }
+
+    pub fn is_range_order(&self) -> bool {
+        matches!(self, VaultMode::RangeOrder { .. })
+    }
+}
+
+// progress of a rebalance split across rebalance_withdraw, rebalance_swap and rebalance_deposit
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RebalanceState {
+    Idle,
+    Withdrawn,
+    Swapped,
+    // no longer written, rebalance_deposit goes back to Idle
+    Deposited,
+}
+
+impl RebalanceState {
+    // liquidity is sitting in the vault token accounts and the position is closed
+    pub fn in_progress(&self) -> bool {
+        matches!(self, RebalanceState::Withdrawn | RebalanceState::Swapped)
     }
 }
 
 pub fn handler(
     ctx: Context<InitializeVault>,
-    pool_position_mint_seed: String,
-    lower_tick_index: i32,
-    upper_tick_index: i32,
+    range_spec: RangeSpec,
+    mode: VaultMode,
 ) -> Result<()> {
+    // whirlpool only accepts ticks on the pool's tick_spacing
+    let (lower_tick_index, upper_tick_index) =
+        range_spec.to_tick_range(ctx.accounts.pool.sqrt_price, ctx.accounts.pool.tick_spacing)?;
+    msg!(
+        "mode: {:?}, lower_tick_index: {}, upper_tick_index: {}",
+        mode,
+        lower_tick_index,
+        upper_tick_index
+    );
+
+    // a range order has to start out holding only the token it sells
+    if let VaultMode::RangeOrder { sell_a } = mode {
+        if !math::range_order_resting(
+            sell_a,
+            ctx.accounts.pool.tick_current_index,
+            lower_tick_index,
+            upper_tick_index,
+        ) {
+            return Err(error!(BloomErrorCode::InvalidRangeOrder));
+        }
+    }
+
     let open_position_accounts = OpenPosition {
         funder: ctx.accounts.admin.to_account_info(),
         owner: ctx.accounts.vault_manager.to_account_info(),
@@ -102,7 +623,7 @@ pub fn handler(
             open_position_accounts,
             &[&[
                 b"pool_position_mint",
-                pool_position_mint_seed.as_bytes(),
+                0u64.to_le_bytes().as_ref(),
                 ctx.accounts.pool.key().as_ref(),
                 &[*ctx.bumps.get("pool_position_mint").unwrap()],
             ]],
@@ -126,9 +647,40 @@ pub fn handler(
     vault_manager.token_b_pool_vault = ctx.accounts.token_b_pool_vault.key();
     vault_manager.pool_position = ctx.accounts.pool_position.key();
     vault_manager.pool_position_mint = ctx.accounts.pool_position_mint.key();
-    vault_manager.pool_position_mint_seed = pool_position_mint_seed;
+    vault_manager.position_nonce = 0;
     vault_manager.pool_position_token_account = ctx.accounts.pool_position_token_account.key();
     vault_manager.admin = ctx.accounts.admin.key();
+    vault_manager.rebalance_state = RebalanceState::Idle;
+    vault_manager.access_mode = AccessMode::Open;
+    vault_manager.config_timelock = DEFAULT_CONFIG_TIMELOCK;
+    vault_manager.version = VAULT_MANAGER_VERSION;
+    vault_manager.strategy = Strategy::Manual;
+    vault_manager.mode = mode;
+
+    let now = Clock::get()?.unix_timestamp;
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.vault_manager = vault_manager.key();
+    vault_stats.in_range = true;
+    vault_stats.created_at = now;
+    vault_stats.last_update_timestamp = now;
+
+    let mut rebalance_history = ctx.accounts.rebalance_history.load_init()?;
+    rebalance_history.vault_manager = vault_manager.key();
+
+    let mut price_observations = ctx.accounts.price_observations.load_init()?;
+    price_observations.vault_manager = vault_manager.key();
+
+    emit!(VaultInitialized {
+        vault_manager: vault_manager.key(),
+        pool: vault_manager.pool,
+        token_a: vault_manager.token_a,
+        token_b: vault_manager.token_b,
+        pool_token: vault_manager.pool_token,
+        pool_position: vault_manager.pool_position,
+        admin: vault_manager.admin,
+        tick_lower_index: lower_tick_index,
+        tick_upper_index: upper_tick_index,
+    });
 
     Ok(())
 }
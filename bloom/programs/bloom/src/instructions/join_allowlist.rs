@@ -0,0 +1,35 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak::hashv;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::math;
+
+#[derive(Accounts)]
+pub struct JoinAllowlist<'info> {
+    #[account(constraint = vault_manager.access_mode == AccessMode::MerkleRoot @ BloomErrorCode::NotAllowlisted)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    // re-joining after the root is rotated updates the existing entry
+    #[account(init_if_needed, payer = user, space = AllowlistEntry::space(), seeds = [b"allowlist", vault_manager.key().as_ref(), user.key().as_ref()], bump)]
+    pub allowlist_entry: Account<'info, AllowlistEntry>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handler(ctx: Context<JoinAllowlist>, proof: Vec<[u8; 32]>) -> Result<()> {
+    let leaf = hashv(&[ctx.accounts.user.key().as_ref()]).0;
+    if !math::verify_merkle_proof(&proof, ctx.accounts.vault_manager.merkle_root, leaf) {
+        return Err(error!(BloomErrorCode::InvalidMerkleProof));
+    }
+
+    let allowlist_entry = &mut ctx.accounts.allowlist_entry;
+    allowlist_entry.vault_manager = ctx.accounts.vault_manager.key();
+    allowlist_entry.user = ctx.accounts.user.key();
+    allowlist_entry.merkle_root = ctx.accounts.vault_manager.merkle_root;
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use anchor_lang::prelude::*;
+use anchor_lang::system_program;
+use anchor_lang::Discriminator;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+
+#[derive(Accounts)]
+pub struct MigrateVault<'info> {
+    /// CHECK: vaults created before the current layout can't be deserialized until they are resized, checked inside instruction
+    #[account(mut, owner = crate::ID)]
+    pub vault_manager: UncheckedAccount<'info>,
+
+    #[account(init_if_needed, payer = admin, space = VaultStats::space(), seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
+    #[account(init_if_needed, payer = admin, space = 8 + std::mem::size_of::<RebalanceHistory>(), seeds = [b"rebalance_history", vault_manager.key().as_ref()], bump)]
+    pub rebalance_history: AccountLoader<'info, RebalanceHistory>,
+
+    #[account(init_if_needed, payer = admin, space = 8 + std::mem::size_of::<PriceObservations>(), seeds = [b"price_observations", vault_manager.key().as_ref()], bump)]
+    pub price_observations: AccountLoader<'info, PriceObservations>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn handler(ctx: Context<MigrateVault>) -> Result<()> {
+    let vault_manager_info = ctx.accounts.vault_manager.to_account_info();
+
+    if vault_manager_info.data_len() < 8
+        || vault_manager_info.try_borrow_data()?[..8] != VaultManager::discriminator()
+    {
+        return Err(error!(BloomErrorCode::InvalidVaultManager));
+    }
+
+    // fields are only ever appended, so zero filling the new space gives an old account the new layout
+    let space = VaultManager::space();
+    if vault_manager_info.data_len() < space {
+        let lamports_required = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(vault_manager_info.lamports());
+
+        if lamports_required > 0 {
+            let transfer_accounts = system_program::Transfer {
+                from: ctx.accounts.admin.to_account_info(),
+                to: vault_manager_info.clone(),
+            };
+
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    transfer_accounts,
+                ),
+                lamports_required,
+            )?;
+        }
+
+        vault_manager_info.realloc(space, true)?;
+    }
+
+    let mut vault_manager =
+        VaultManager::try_deserialize(&mut &vault_manager_info.try_borrow_data()?[..])?;
+
+    if vault_manager.admin != ctx.accounts.admin.key() {
+        return Err(error!(BloomErrorCode::InvalidVaultManager));
+    }
+
+    if vault_manager.version < VAULT_MANAGER_VERSION {
+        // unversioned vaults read 0 for everything added since, restore the initialize_vault defaults
+        if vault_manager.version == 0 {
+            vault_manager.config_timelock = DEFAULT_CONFIG_TIMELOCK;
+        }
+
+        vault_manager.version = VAULT_MANAGER_VERSION;
+        vault_manager.try_serialize(&mut &mut vault_manager_info.try_borrow_mut_data()?[..])?;
+    }
+    msg!("vault_manager_version: {}", vault_manager.version);
+
+    // accounts added after the vault was created
+    let now = Clock::get()?.unix_timestamp;
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    if vault_stats.vault_manager == Pubkey::default() {
+        vault_stats.vault_manager = vault_manager_info.key();
+        vault_stats.in_range = true;
+        vault_stats.created_at = now;
+        vault_stats.last_update_timestamp = now;
+    }
+
+    if let Ok(mut rebalance_history) = ctx.accounts.rebalance_history.load_init() {
+        rebalance_history.vault_manager = vault_manager_info.key();
+    }
+
+    if let Ok(mut price_observations) = ctx.accounts.price_observations.load_init() {
+        price_observations.vault_manager = vault_manager_info.key();
+    }
+
+    Ok(())
+}
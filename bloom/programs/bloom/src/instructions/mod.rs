@@ -1,9 +1,43 @@
 pub mod add_liquidity;
+pub mod add_liquidity_single_sided;
+pub mod add_to_allowlist;
+pub mod cancel_config_change;
+pub mod execute_config_change;
 pub mod initialize_vault;
+pub mod join_allowlist;
+pub mod migrate_vault;
+pub mod preview_deposit;
+pub mod preview_rebalance;
+pub mod preview_withdraw;
+pub mod queue_config_change;
+pub mod rebalance_deposit;
 pub mod rebalance_positions;
+pub mod rebalance_swap;
+pub mod rebalance_withdraw;
+pub mod remove_from_allowlist;
 pub mod remove_liquidity;
+pub mod remove_liquidity_single_sided;
+pub mod retarget_rebalance;
+pub mod vault_view;
 
 pub use add_liquidity::*;
+pub use add_liquidity_single_sided::*;
+pub use add_to_allowlist::*;
+pub use cancel_config_change::*;
+pub use execute_config_change::*;
 pub use initialize_vault::*;
+pub use join_allowlist::*;
+pub use migrate_vault::*;
+pub use preview_deposit::*;
+pub use preview_rebalance::*;
+pub use preview_withdraw::*;
+pub use queue_config_change::*;
+pub use rebalance_deposit::*;
 pub use rebalance_positions::*;
+pub use rebalance_swap::*;
+pub use rebalance_withdraw::*;
+pub use remove_from_allowlist::*;
 pub use remove_liquidity::*;
+pub use remove_liquidity_single_sided::*;
+pub use retarget_rebalance::*;
+pub use vault_view::*;
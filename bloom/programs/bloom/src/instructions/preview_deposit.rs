@@ -0,0 +1,88 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::set_return_data;
+use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
+use anchor_spl::token;
+use whirlpool::{
+    manager::liquidity_manager::calculate_liquidity_token_deltas, math::sqrt_price_from_tick_index,
+    state::Position as WhirlpoolPosition,
+};
+use whirlpools::state::Whirlpool;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+
+#[derive(Accounts)]
+pub struct PreviewDeposit<'info> {
+    #[account(seeds = [pool.key().as_ref()], bump,
+        has_one = pool_position,
+        has_one = pool,
+        has_one = pool_token)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(seeds = [b"pool_token", vault_manager.key().as_ref()], bump)]
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    /// CHECK: deserialized inside instruction
+    pub pool_position: UncheckedAccount<'info>,
+}
+
+// returned by preview_deposit, the token amounts are the maximums add_liquidity transfers
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DepositPreview {
+    pub liquidity: u128,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub pool_token_amount: u64,
+}
+
+// runs the add_liquidity math against the current accounts without moving any tokens
+pub fn handler(ctx: Context<PreviewDeposit>, deposit_mode: DepositMode) -> Result<()> {
+    // Have to do this hacky thing because of anchor-lang version mismatch
+    let pool_position =
+        WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
+
+    let tick_index_current = ctx.accounts.pool.tick_current_index;
+
+    if !ctx.accounts.vault_manager.mode.is_range_order()
+        && !position_in_range(
+            tick_index_current,
+            pool_position.tick_lower_index,
+            pool_position.tick_upper_index,
+        )
+    {
+        return Err(error!(BloomErrorCode::PositionOutOfRange));
+    }
+
+    let sqrt_price_current_x64 = ctx.accounts.pool.sqrt_price;
+
+    let (liquidity, pool_token_amount) = calculate_deposit(
+        deposit_mode,
+        sqrt_price_from_tick_index(pool_position.tick_lower_index),
+        sqrt_price_current_x64,
+        sqrt_price_from_tick_index(pool_position.tick_upper_index),
+        pool_position.liquidity,
+        ctx.accounts.pool_token.supply,
+    )?;
+
+    let (token_a_amount, token_b_amount) = calculate_liquidity_token_deltas(
+        tick_index_current,
+        sqrt_price_current_x64,
+        &pool_position,
+        liquidity as i128,
+    )
+    .map_err(|_| error!(BloomErrorCode::Miscalculation))?;
+
+    let preview = DepositPreview {
+        liquidity,
+        token_a_amount,
+        token_b_amount,
+        pool_token_amount,
+    };
+    msg!("{:?}", preview);
+
+    set_return_data(&preview.try_to_vec()?);
+
+    Ok(())
+}
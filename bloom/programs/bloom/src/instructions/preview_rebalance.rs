@@ -0,0 +1,141 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::set_return_data;
+use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
+use anchor_spl::token;
+use whirlpool::{
+    manager::liquidity_manager::calculate_liquidity_token_deltas, math::sqrt_price_from_tick_index,
+    state::Position as WhirlpoolPosition,
+};
+use whirlpools::state::Whirlpool;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::math;
+
+#[derive(Accounts)]
+pub struct PreviewRebalance<'info> {
+    #[account(seeds = [pool.key().as_ref()], bump,
+        has_one = pool_position,
+        has_one = pool,
+        has_one = token_a,
+        has_one = token_b,
+        has_one = token_a_vault,
+        has_one = token_b_vault,
+        constraint = !vault_manager.mode.is_range_order() @ BloomErrorCode::InvalidVaultMode)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    pub token_a: Box<Account<'info, token::Mint>>,
+
+    pub token_b: Box<Account<'info, token::Mint>>,
+
+    pub token_a_vault: Box<Account<'info, token::TokenAccount>>,
+
+    pub token_b_vault: Box<Account<'info, token::TokenAccount>>,
+
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    /// CHECK: deserialized inside instruction
+    pub pool_position: UncheckedAccount<'info>,
+}
+
+// returned by preview_rebalance, token amounts are the vault balances once the position is withdrawn
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RebalancePreview {
+    pub rebalance_needed: bool,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub a_to_b: bool,
+    pub swap_amount: u64,
+}
+
+// runs the rebalance_positions swap math for a target range without moving any tokens
+pub fn handler(
+    ctx: Context<PreviewRebalance>,
+    lower_tick_index: i32,
+    upper_tick_index: i32,
+) -> Result<()> {
+    // whirlpool only accepts ticks on the pool's tick_spacing
+    let (lower_tick_index, upper_tick_index) = math::snap_tick_range(
+        lower_tick_index,
+        upper_tick_index,
+        ctx.accounts.pool.tick_spacing,
+    )
+    .ok_or_else(|| error!(BloomErrorCode::InvalidTickRange))?;
+    msg!(
+        "lower_tick_index: {}, upper_tick_index: {}",
+        lower_tick_index,
+        upper_tick_index
+    );
+
+    // Have to do this hacky thing because of anchor-lang version mismatch
+    let pool_position =
+        WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
+
+    let tick_index_current = ctx.accounts.pool.tick_current_index;
+
+    let mut preview = RebalancePreview {
+        rebalance_needed: false,
+        token_a_amount: 0,
+        token_b_amount: 0,
+        a_to_b: false,
+        swap_amount: 0,
+    };
+
+    // rebalance_positions is a no-op while the position is in range
+    if position_in_range(
+        tick_index_current,
+        pool_position.tick_lower_index,
+        pool_position.tick_upper_index,
+    ) {
+        msg!("{:?}", preview);
+        set_return_data(&preview.try_to_vec()?);
+        return Ok(());
+    }
+
+    if !position_in_range(tick_index_current, lower_tick_index, upper_tick_index) {
+        return Err(error!(BloomErrorCode::PositionOutOfRange));
+    }
+
+    let (token_a_withdrawn, token_b_withdrawn) = calculate_liquidity_token_deltas(
+        tick_index_current,
+        ctx.accounts.pool.sqrt_price,
+        &pool_position,
+        -(pool_position.liquidity as i128),
+    )
+    .unwrap();
+
+    // fees owed are only settled when the position is touched, so this can undercount them
+    preview.rebalance_needed = true;
+    preview.token_a_amount =
+        ctx.accounts.token_a_vault.amount + token_a_withdrawn + pool_position.fee_owed_a;
+    preview.token_b_amount =
+        ctx.accounts.token_b_vault.amount + token_b_withdrawn + pool_position.fee_owed_b;
+
+    let decimals_a = ctx.accounts.token_a.decimals;
+    let decimals_b = ctx.accounts.token_b.decimals;
+
+    let (a_to_b, swap_amount) = math::calculate_rebalance_swap(
+        math::sqrt_price_x64_to_price(
+            sqrt_price_from_tick_index(lower_tick_index),
+            decimals_a,
+            decimals_b,
+        ),
+        math::sqrt_price_x64_to_price(ctx.accounts.pool.sqrt_price, decimals_a, decimals_b),
+        math::sqrt_price_x64_to_price(
+            sqrt_price_from_tick_index(upper_tick_index),
+            decimals_a,
+            decimals_b,
+        ),
+        preview.token_a_amount,
+        preview.token_b_amount,
+        decimals_a,
+        decimals_b,
+    );
+    preview.a_to_b = a_to_b;
+    preview.swap_amount = swap_amount;
+    msg!("{:?}", preview);
+
+    set_return_data(&preview.try_to_vec()?);
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::set_return_data;
+use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
+use anchor_spl::token;
+use whirlpool::{
+    manager::liquidity_manager::calculate_liquidity_token_deltas,
+    state::Position as WhirlpoolPosition,
+};
+use whirlpools::state::Whirlpool;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+
+#[derive(Accounts)]
+pub struct PreviewWithdraw<'info> {
+    #[account(seeds = [pool.key().as_ref()], bump,
+        has_one = pool_position,
+        has_one = pool,
+        has_one = pool_token)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(seeds = [b"pool_token", vault_manager.key().as_ref()], bump)]
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    /// CHECK: deserialized inside instruction
+    pub pool_position: UncheckedAccount<'info>,
+
+    /// CHECK: only the key is used, the withdraw is previewed for this user
+    pub user: UncheckedAccount<'info>,
+
+    #[account(associated_token::mint = pool_token, associated_token::authority = user)]
+    pub user_pool_token_ata: Account<'info, token::TokenAccount>,
+
+    /// CHECK: deserialized inside instruction, remove_liquidity creates it if it does not exist yet
+    #[account(seeds = [b"user_position", vault_manager.key().as_ref(), user.key().as_ref()], bump)]
+    pub user_position: UncheckedAccount<'info>,
+}
+
+// returned by preview_withdraw, the token amounts are what remove_liquidity pays out after the
+// early exit fee
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WithdrawPreview {
+    pub liquidity: u128,
+    pub early_exit_fee: u128,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+}
+
+// runs the remove_liquidity math for an amount of pool tokens without moving any tokens
+pub fn handler(ctx: Context<PreviewWithdraw>, pool_token_amount: u64) -> Result<()> {
+    // Have to do this hacky thing because of anchor-lang version mismatch
+    let pool_position =
+        WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
+
+    let (liquidity, _pool_token_amount) = calculate_withdraw(
+        WithdrawMode::ExactShares { pool_token_amount },
+        pool_token_amount,
+        pool_position.liquidity,
+        ctx.accounts.pool_token.supply,
+    )?;
+
+    // same early exit fee, or DepositLocked, as remove_liquidity
+    let user_pool_token_amount = ctx.accounts.user_pool_token_ata.amount;
+    if pool_token_amount > user_pool_token_amount {
+        return Err(error!(BloomErrorCode::InsufficientPoolTokens));
+    }
+    let (untracked_pool_tokens, last_deposit_timestamp) =
+        if ctx.accounts.user_position.data_is_empty() {
+            (user_pool_token_amount, 0)
+        } else {
+            let user_position = Account::<UserPosition>::try_from(&ctx.accounts.user_position)?;
+            (
+                user_position.untracked_pool_tokens(user_pool_token_amount),
+                user_position.last_deposit_timestamp,
+            )
+        };
+    let early_exit_fee = ctx.accounts.vault_manager.early_exit_fee(
+        liquidity,
+        pool_token_amount,
+        untracked_pool_tokens,
+        Clock::get()?.unix_timestamp,
+        last_deposit_timestamp,
+    )?;
+    let liquidity = liquidity - early_exit_fee;
+
+    // a negative delta rounds down, same as decrease_liquidity
+    let (token_a_amount, token_b_amount) = calculate_liquidity_token_deltas(
+        ctx.accounts.pool.tick_current_index,
+        ctx.accounts.pool.sqrt_price,
+        &pool_position,
+        -(liquidity as i128),
+    )
+    .map_err(|_| error!(BloomErrorCode::Miscalculation))?;
+
+    let preview = WithdrawPreview {
+        liquidity,
+        early_exit_fee,
+        token_a_amount,
+        token_b_amount,
+    };
+    msg!("{:?}", preview);
+
+    set_return_data(&preview.try_to_vec()?);
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use anchor_lang::prelude::*;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::ConfigChangeQueued;
+use crate::strategy::Strategy;
+
+#[derive(Accounts)]
+pub struct QueueConfigChange<'info> {
+    #[account(has_one = admin)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    // one change at a time, cancel_config_change before queueing another
+    #[account(init, payer = admin, space = PendingConfig::space(), seeds = [b"pending_config", vault_manager.key().as_ref()], bump)]
+    pub pending_config: Box<Account<'info, PendingConfig>>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// vault parameters the admin can tune after initialize_vault
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VaultConfig {
+    pub min_deposit_duration: i64,
+    pub early_exit_fee_bps: u16,
+    pub max_total_liquidity: u128,
+    pub max_per_user_shares: u64,
+    pub access_mode: AccessMode,
+    pub merkle_root: [u8; 32],
+    pub config_timelock: i64,
+    pub strategy: Strategy,
+}
+
+impl VaultConfig {
+    pub fn space() -> usize {
+        8 + 2 + 16 + 8 + 1 + 32 + 8 + Strategy::space()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.early_exit_fee_bps > 10_000 {
+            return Err(error!(BloomErrorCode::InvalidFee));
+        }
+
+        if self.min_deposit_duration < 0 || self.config_timelock < 0 || !self.strategy.is_valid() {
+            return Err(error!(BloomErrorCode::InvalidConfig));
+        }
+
+        Ok(())
+    }
+}
+
+#[account]
+pub struct PendingConfig {
+    pub vault_manager: Pubkey,
+    pub config: VaultConfig,
+    // earliest unix timestamp execute_config_change accepts
+    pub eta: i64,
+}
+
+impl PendingConfig {
+    pub fn space() -> usize {
+        8 + 32 + VaultConfig::space() + 8
+    }
+}
+
+pub fn handler(ctx: Context<QueueConfigChange>, config: VaultConfig) -> Result<()> {
+    config.validate()?;
+
+    // the current timelock applies, including to changes of the timelock itself
+    let eta = Clock::get()?.unix_timestamp + ctx.accounts.vault_manager.config_timelock;
+
+    let pending_config = &mut ctx.accounts.pending_config;
+    pending_config.vault_manager = ctx.accounts.vault_manager.key();
+    pending_config.config = config;
+    pending_config.eta = eta;
+
+    emit!(ConfigChangeQueued {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        config,
+        eta,
+    });
+
+    Ok(())
+}
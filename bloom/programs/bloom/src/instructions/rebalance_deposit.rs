@@ -0,0 +1,328 @@
+use anchor_lang::prelude::*;
+use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
+use anchor_spl::{associated_token, token};
+use whirlpool::{
+    manager::liquidity_manager::calculate_liquidity_token_deltas, math::sqrt_price_from_tick_index,
+    state::Position as WhirlpoolPosition,
+};
+use whirlpools::cpi::{
+    accounts::{IncreaseLiquidity, OpenPosition},
+    {increase_liquidity, open_position},
+};
+use whirlpools::program::Whirlpool as WhirlpoolProgram;
+use whirlpools::state::{TickArray, Whirlpool};
+use whirlpools::OpenPositionBumps;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::{RebalanceDeposited, Rebalanced};
+use crate::math;
+
+#[derive(Accounts)]
+pub struct RebalanceDeposit<'info> {
+    #[account(mut,
+        seeds = [pool.key().as_ref()], bump,
+        has_one = token_a,
+        has_one = token_b,
+        has_one = token_a_vault,
+        has_one = token_b_vault,
+        has_one = pool,
+        has_one = pool_token,
+        has_one = token_a_pool_vault,
+        has_one = token_b_pool_vault,
+        constraint = vault_manager.rebalance_state == RebalanceState::Swapped @ BloomErrorCode::InvalidRebalanceState)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(mut, seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
+    #[account(mut, seeds = [b"rebalance_history", vault_manager.key().as_ref()], bump)]
+    pub rebalance_history: AccountLoader<'info, RebalanceHistory>,
+
+    pub token_a: Box<Account<'info, token::Mint>>,
+
+    pub token_b: Box<Account<'info, token::Mint>>,
+
+    #[account(seeds = [b"pool_token", vault_manager.key().as_ref()], bump)]
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_a.key().as_ref()], bump, token::mint = token_a, token::authority = vault_manager)]
+    pub token_a_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_b.key().as_ref()], bump, token::mint = token_b, token::authority = vault_manager)]
+    pub token_b_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    #[account(mut, token::mint = token_a)]
+    pub token_a_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, token::mint = token_b)]
+    pub token_b_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub new_tick_array_lower: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub new_tick_array_upper: AccountLoader<'info, TickArray>,
+
+    /// CHECK: initialized by whirlpool program
+    #[account(mut, seeds = [b"position", new_pool_position_mint.key().as_ref()], bump, seeds::program = whirlpool_program)]
+    pub new_pool_position: UncheckedAccount<'info>,
+
+    /// CHECK: initialized by whirlpool program
+    #[account(mut, seeds = [b"pool_position_mint", vault_manager.next_position_nonce().to_le_bytes().as_ref(), pool.key().as_ref()], bump)]
+    pub new_pool_position_mint: UncheckedAccount<'info>,
+
+    /// CHECK: initialized by whirlpool program
+    #[account(mut)]
+    pub new_pool_position_token_account: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub crank: Signer<'info>,
+
+    pub token_program: Program<'info, token::Token>,
+    pub whirlpool_program: Program<'info, WhirlpoolProgram>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+    pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
+}
+
+// last step of a rebalance: open the target position and deposit the swapped vault balances
+pub fn handler(ctx: Context<RebalanceDeposit>) -> Result<()> {
+    let tick_index_current = ctx.accounts.pool.tick_current_index;
+
+    // price may have moved since rebalance_withdraw, the admin can pick a new range with
+    // retarget_rebalance
+    if !position_in_range(
+        tick_index_current,
+        ctx.accounts.vault_manager.rebalance_lower_tick_index,
+        ctx.accounts.vault_manager.rebalance_upper_tick_index,
+    ) {
+        return Err(error!(BloomErrorCode::PositionOutOfRange));
+    }
+
+    check_tick_array(
+        &ctx.accounts.new_tick_array_lower.to_account_info(),
+        &ctx.accounts.pool,
+        ctx.accounts.vault_manager.rebalance_lower_tick_index,
+        BloomErrorCode::InvalidTickArrayLower,
+    )?;
+    check_tick_array(
+        &ctx.accounts.new_tick_array_upper.to_account_info(),
+        &ctx.accounts.pool,
+        ctx.accounts.vault_manager.rebalance_upper_tick_index,
+        BloomErrorCode::InvalidTickArrayUpper,
+    )?;
+
+    // open new position
+    let open_position_accounts = OpenPosition {
+        funder: ctx.accounts.crank.to_account_info(),
+        owner: ctx.accounts.vault_manager.to_account_info(),
+        position: ctx.accounts.new_pool_position.to_account_info(),
+        position_mint: ctx.accounts.new_pool_position_mint.to_account_info(),
+        position_token_account: ctx
+            .accounts
+            .new_pool_position_token_account
+            .to_account_info(),
+        whirlpool: ctx.accounts.pool.to_account_info(),
+        token_program: ctx.accounts.token_program.to_account_info(),
+        system_program: ctx.accounts.system_program.to_account_info(),
+        rent: ctx.accounts.rent.to_account_info(),
+        associated_token_program: ctx.accounts.associated_token_program.to_account_info(),
+    };
+
+    open_position(
+        CpiContext::new_with_signer(
+            ctx.accounts.whirlpool_program.to_account_info(),
+            open_position_accounts,
+            &[&[
+                b"pool_position_mint",
+                ctx.accounts
+                    .vault_manager
+                    .next_position_nonce()
+                    .to_le_bytes()
+                    .as_ref(),
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("new_pool_position_mint").unwrap()],
+            ]],
+        ),
+        OpenPositionBumps {
+            position_bump: *ctx.bumps.get("new_pool_position").unwrap(),
+        },
+        ctx.accounts.vault_manager.rebalance_lower_tick_index,
+        ctx.accounts.vault_manager.rebalance_upper_tick_index,
+    )?;
+
+    let new_pool_position =
+        &mut WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.new_pool_position)?;
+
+    let sqrt_price_current_x64 = ctx.accounts.pool.sqrt_price;
+    let sqrt_price_lower_x64 = sqrt_price_from_tick_index(new_pool_position.tick_lower_index);
+    let sqrt_price_upper_x64 = sqrt_price_from_tick_index(new_pool_position.tick_upper_index);
+
+    // the swap only approximates the deposit ratio, so deposit the most liquidity both
+    // balances can support and leave the rest idle for the next compound
+    let liquidity = math::get_liquidity_for_amounts(
+        ctx.accounts.token_a_vault.amount as u128,
+        ctx.accounts.token_b_vault.amount as u128,
+        sqrt_price_lower_x64,
+        sqrt_price_current_x64,
+        sqrt_price_upper_x64,
+    );
+
+    let (token_a_needed, token_b_needed) = calculate_liquidity_token_deltas(
+        tick_index_current,
+        sqrt_price_current_x64,
+        &new_pool_position,
+        liquidity as i128,
+    )
+    .unwrap();
+
+    // the token deltas round up and can ask for a unit more than the vault holds, scale the
+    // liquidity down to what the balances support instead of failing the rebalance
+    let liquidity = math::scale_liquidity_to_balances(
+        liquidity,
+        token_a_needed,
+        token_b_needed,
+        ctx.accounts.token_a_vault.amount,
+        ctx.accounts.token_b_vault.amount,
+    );
+
+    let (token_max_a, token_max_b) = calculate_liquidity_token_deltas(
+        tick_index_current,
+        sqrt_price_current_x64,
+        &new_pool_position,
+        liquidity as i128,
+    )
+    .unwrap();
+    let token_max_a = token_max_a.min(ctx.accounts.token_a_vault.amount);
+    let token_max_b = token_max_b.min(ctx.accounts.token_b_vault.amount);
+
+    msg!(
+        "liquidity: {}, token_max_a: {}, token_max_b: {}",
+        liquidity,
+        token_max_a,
+        token_max_b,
+    );
+
+    // add liquidity into the new position
+    let increase_liquidity_accounts = IncreaseLiquidity {
+        whirlpool: ctx.accounts.pool.to_account_info(),
+        position: ctx.accounts.new_pool_position.to_account_info(),
+        position_authority: ctx.accounts.vault_manager.to_account_info(),
+        position_token_account: ctx
+            .accounts
+            .new_pool_position_token_account
+            .to_account_info(),
+        tick_array_lower: ctx.accounts.new_tick_array_lower.to_account_info(),
+        tick_array_upper: ctx.accounts.new_tick_array_upper.to_account_info(),
+        token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
+        token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
+        token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
+        token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
+        token_program: ctx.accounts.token_program.to_account_info(),
+    };
+
+    increase_liquidity(
+        CpiContext::new_with_signer(
+            ctx.accounts.whirlpool_program.to_account_info(),
+            increase_liquidity_accounts,
+            &[&[
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("vault_manager").unwrap()],
+            ]],
+        ),
+        liquidity,
+        token_max_a,
+        token_max_b,
+    )?;
+
+    ctx.accounts.token_a_vault.reload()?;
+    ctx.accounts.token_b_vault.reload()?;
+
+    msg!(
+        "after rebalance_deposit\tvault_a: {}, vault_b: {}",
+        ctx.accounts.token_a_vault.amount,
+        ctx.accounts.token_b_vault.amount
+    );
+
+    // whatever did not fit the new range stays in the vault token accounts
+    emit!(RebalanceDeposited {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        new_tick_lower_index: new_pool_position.tick_lower_index,
+        new_tick_upper_index: new_pool_position.tick_upper_index,
+        sqrt_price_x64: sqrt_price_current_x64,
+        liquidity,
+        token_a_leftover: ctx.accounts.token_a_vault.amount,
+        token_b_leftover: ctx.accounts.token_b_vault.amount,
+    });
+
+    let clock = Clock::get()?;
+
+    let mut rebalance_history = ctx.accounts.rebalance_history.load_mut()?;
+    let entry = rebalance_history.pending_mut();
+    entry.slot = clock.slot;
+    entry.new_tick_lower_index = new_pool_position.tick_lower_index;
+    entry.new_tick_upper_index = new_pool_position.tick_upper_index;
+    entry.sqrt_price_after_x64 = sqrt_price_current_x64;
+    entry.token_a_leftover = ctx.accounts.token_a_vault.amount;
+    entry.token_b_leftover = ctx.accounts.token_b_vault.amount;
+    let entry = *entry;
+    rebalance_history.commit();
+
+    // the same summary rebalance_positions emits, put together from the three steps
+    emit!(Rebalanced {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        old_tick_lower_index: entry.old_tick_lower_index,
+        old_tick_upper_index: entry.old_tick_upper_index,
+        new_tick_lower_index: entry.new_tick_lower_index,
+        new_tick_upper_index: entry.new_tick_upper_index,
+        new_price_lower_x64: math::tick_index_to_price_x64(entry.new_tick_lower_index),
+        new_price_upper_x64: math::tick_index_to_price_x64(entry.new_tick_upper_index),
+        range_spec: RangeSpec::Ticks {
+            lower_tick_index: entry.new_tick_lower_index,
+            upper_tick_index: entry.new_tick_upper_index,
+        },
+        sqrt_price_x64: sqrt_price_current_x64,
+        a_to_b: entry.a_to_b == 1,
+        swap_amount_in: entry.swap_amount_in,
+        swap_amount_out: entry.swap_amount_out,
+        fee_a: ctx.accounts.vault_manager.rebalance_fee_a,
+        fee_b: ctx.accounts.vault_manager.rebalance_fee_b,
+        liquidity,
+        token_a_leftover: entry.token_a_leftover,
+        token_b_leftover: entry.token_b_leftover,
+    });
+
+    let now = clock.unix_timestamp;
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.update_time(
+        now,
+        position_in_range(
+            tick_index_current,
+            new_pool_position.tick_lower_index,
+            new_pool_position.tick_upper_index,
+        ),
+    );
+    vault_stats.update_share_price(
+        liquidity,
+        new_pool_position.tick_lower_index,
+        new_pool_position.tick_upper_index,
+        sqrt_price_current_x64,
+        ctx.accounts.pool_token.supply,
+    );
+    vault_stats.rebalance_count += 1;
+    vault_stats.last_rebalance_timestamp = now;
+
+    // point the vault at the new position and unlock deposits and withdrawals
+    let vault_manager = &mut ctx.accounts.vault_manager;
+    vault_manager.pool_position = ctx.accounts.new_pool_position.key();
+    vault_manager.pool_position_mint = ctx.accounts.new_pool_position_mint.key();
+    vault_manager.pool_position_token_account = ctx.accounts.new_pool_position_token_account.key();
+    vault_manager.position_nonce += 1;
+    vault_manager.rebalance_state = RebalanceState::Idle;
+
+    Ok(())
+}
@@ -1,4 +1,9 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{
+    hash::hash,
+    instruction::{AccountMeta, Instruction},
+    program::{get_return_data, invoke},
+};
 use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
 use anchor_spl::{associated_token, token};
 use whirlpool::{
@@ -19,10 +24,11 @@ use whirlpools::OpenPositionBumps;
 
 use super::*;
 use crate::errors::BloomErrorCode;
+use crate::events::{FeesCollected, PositionInRange, RangeOrderFilled, Rebalanced};
 use crate::math;
+use crate::strategy::Strategy;
 
 #[derive(Accounts)]
-#[instruction(new_pool_position_mint_seed: String)]
 pub struct RebalancePositions<'info> {
     #[account(mut,
         seeds = [pool.key().as_ref()], bump,
@@ -31,14 +37,28 @@ pub struct RebalancePositions<'info> {
         has_one = token_a_vault,
         has_one = token_b_vault,
         has_one = pool,
+        has_one = pool_token,
         has_one = token_a_pool_vault,
-        has_one = token_b_pool_vault)]
+        has_one = token_b_pool_vault,
+        constraint = !vault_manager.rebalance_state.in_progress() @ BloomErrorCode::RebalanceInProgress)]
     pub vault_manager: Box<Account<'info, VaultManager>>,
 
+    #[account(mut, seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
+    #[account(mut, seeds = [b"rebalance_history", vault_manager.key().as_ref()], bump)]
+    pub rebalance_history: AccountLoader<'info, RebalanceHistory>,
+
+    #[account(mut, seeds = [b"price_observations", vault_manager.key().as_ref()], bump)]
+    pub price_observations: AccountLoader<'info, PriceObservations>,
+
     pub token_a: Box<Account<'info, token::Mint>>,
 
     pub token_b: Box<Account<'info, token::Mint>>,
 
+    #[account(seeds = [b"pool_token", vault_manager.key().as_ref()], bump)]
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
     #[account(mut, seeds = [vault_manager.key().as_ref(), token_a.key().as_ref()], bump, token::mint = token_a, token::authority = vault_manager)]
     pub token_a_vault: Box<Account<'info, token::TokenAccount>>,
 
@@ -58,7 +78,7 @@ pub struct RebalancePositions<'info> {
     #[account(mut, seeds = [b"position", old_pool_position_mint.key().as_ref()], bump, seeds::program = whirlpool_program)]
     pub old_pool_position: UncheckedAccount<'info>,
 
-    #[account(mut, seeds = [b"pool_position_mint", vault_manager.pool_position_mint_seed.as_bytes(), pool.key().as_ref()], bump)]
+    #[account(mut, address = vault_manager.pool_position_mint)]
     pub old_pool_position_mint: Account<'info, token::Mint>,
 
     #[account(mut, token::mint = old_pool_position_mint, token::authority = vault_manager)]
@@ -76,6 +96,13 @@ pub struct RebalancePositions<'info> {
     #[account(mut)]
     pub tick_array_current: AccountLoader<'info, TickArray>,
 
+    // the next two tick arrays in the direction of the swap
+    #[account(mut)]
+    pub tick_array_1: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_2: AccountLoader<'info, TickArray>,
+
     #[account(mut)]
     pub new_tick_array_upper: AccountLoader<'info, TickArray>,
 
@@ -88,7 +115,7 @@ pub struct RebalancePositions<'info> {
     pub new_pool_position: UncheckedAccount<'info>,
 
     /// CHECK: initialized by whirlpool program
-    #[account(mut, seeds = [b"pool_position_mint", new_pool_position_mint_seed.as_bytes(), pool.key().as_ref()], bump)]
+    #[account(mut, seeds = [b"pool_position_mint", vault_manager.next_position_nonce().to_le_bytes().as_ref(), pool.key().as_ref()], bump)]
     pub new_pool_position_mint: UncheckedAccount<'info>,
 
     /// CHECK: initialized by whirlpool program
@@ -105,12 +132,7 @@ pub struct RebalancePositions<'info> {
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
 }
 
-pub fn handler(
-    ctx: Context<RebalancePositions>,
-    new_pool_position_mint_seed: String,
-    lower_tick_index: i32,
-    upper_tick_index: i32,
-) -> Result<()> {
+pub fn handler(ctx: Context<RebalancePositions>, range_spec: RangeSpec) -> Result<()> {
     // Have to do this hacky thing because of anchor-lang version mismatch
     let old_pool_position =
         &mut WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.old_pool_position)?;
@@ -119,32 +141,168 @@ pub fn handler(
     let tick_index_upper = old_pool_position.tick_upper_index;
     let tick_index_current = ctx.accounts.pool.tick_current_index;
 
+    // sample on every crank, Strategy::Volatility needs prices from calm periods too
+    ctx.accounts
+        .price_observations
+        .load_mut()?
+        .record(Clock::get()?.unix_timestamp, tick_index_current);
+
     // if the position is in range then return without error
     if position_in_range(tick_index_current, tick_index_lower, tick_index_upper) {
         msg!("position in range, no rebalance");
-        let lower_price = math::sqrt_price_x64_to_price(
-            sqrt_price_from_tick_index(old_pool_position.tick_lower_index),
-            ctx.accounts.token_a.decimals,
-            ctx.accounts.token_b.decimals,
-        );
-        let upper_price = math::sqrt_price_x64_to_price(
-            sqrt_price_from_tick_index(old_pool_position.tick_upper_index),
-            ctx.accounts.token_a.decimals,
-            ctx.accounts.token_b.decimals,
-        );
-        let current_price = math::sqrt_price_x64_to_price(
-            ctx.accounts.pool.sqrt_price,
-            ctx.accounts.token_a.decimals,
-            ctx.accounts.token_b.decimals,
-        );
         emit!(PositionInRange {
-            lower_price: lower_price,
-            current_price: current_price,
-            upper_price: upper_price,
+            vault_manager: ctx.accounts.vault_manager.key(),
+            tick_lower_index: tick_index_lower,
+            tick_upper_index: tick_index_upper,
+            tick_current_index: tick_index_current,
+            sqrt_price_x64: ctx.accounts.pool.sqrt_price,
         });
+
+        let vault_stats = &mut ctx.accounts.vault_stats;
+        vault_stats.update_time(Clock::get()?.unix_timestamp, true);
+        vault_stats.update_share_price(
+            old_pool_position.liquidity,
+            tick_index_lower,
+            tick_index_upper,
+            ctx.accounts.pool.sqrt_price,
+            ctx.accounts.pool_token.supply,
+        );
+        return Ok(());
+    }
+
+    let strategy = ctx.accounts.vault_manager.strategy;
+    let mode = ctx.accounts.vault_manager.mode;
+    let last_rebalance_timestamp = ctx.accounts.vault_stats.last_rebalance_timestamp;
+
+    // trailing strategies wait out moves against their direction, range orders wait to be filled
+    let rebalance = match mode {
+        VaultMode::Liquidity => {
+            strategy.should_rebalance(&ctx.accounts.pool, tick_index_lower, tick_index_upper)
+        }
+        VaultMode::RangeOrder { sell_a } => math::range_order_filled(
+            sell_a,
+            tick_index_current,
+            tick_index_lower,
+            tick_index_upper,
+        ),
+    };
+    if !rebalance {
+        msg!("strategy holding position, no rebalance");
+        ctx.accounts
+            .vault_stats
+            .update_time(Clock::get()?.unix_timestamp, false);
+        return Ok(());
+    }
+
+    if !mode.is_range_order()
+        && !rebalance_price_settled(
+            &*ctx.accounts.price_observations.load()?,
+            tick_index_current,
+            Clock::get()?.unix_timestamp,
+        )
+    {
+        msg!("pool price away from the time weighted price, no rebalance");
+        ctx.accounts
+            .vault_stats
+            .update_time(Clock::get()?.unix_timestamp, false);
         return Ok(());
     }
 
+    // a filled range order is harvested into an order of the same width the other way,
+    // otherwise the crank's range is only used by Strategy::Manual vaults
+    let range_spec = if let VaultMode::RangeOrder { sell_a } = mode {
+        let (lower_tick_index, upper_tick_index) = math::calculate_range_order_tick_range(
+            tick_index_current,
+            ctx.accounts.pool.tick_spacing,
+            tick_index_upper - tick_index_lower,
+            !sell_a,
+        )
+        .ok_or_else(|| error!(BloomErrorCode::InvalidRangeOrder))?;
+
+        RangeSpec::Ticks {
+            lower_tick_index,
+            upper_tick_index,
+        }
+    } else {
+        match strategy {
+            Strategy::External { program, .. } => {
+                let (lower_tick_index, upper_tick_index) = get_external_tick_range(
+                    program,
+                    ctx.accounts.pool.to_account_info(),
+                    ctx.accounts.vault_manager.to_account_info(),
+                    ctx.accounts.vault_stats.to_account_info(),
+                    ctx.remaining_accounts,
+                )?;
+                let reference_tick_index = ctx
+                    .accounts
+                    .price_observations
+                    .load()?
+                    .reference_tick_index(Clock::get()?.unix_timestamp)
+                    .ok_or_else(|| error!(BloomErrorCode::InsufficientPriceObservations))?;
+                strategy.check_external_tick_range(
+                    ctx.accounts.pool.tick_spacing,
+                    reference_tick_index,
+                    lower_tick_index,
+                    upper_tick_index,
+                )?;
+
+                RangeSpec::Ticks {
+                    lower_tick_index,
+                    upper_tick_index,
+                }
+            }
+            Strategy::Volatility { .. } => {
+                let realized_volatility_ticks = ctx
+                    .accounts
+                    .price_observations
+                    .load()?
+                    .realized_volatility_ticks(Clock::get()?.unix_timestamp);
+                msg!("realized_volatility_ticks: {}", realized_volatility_ticks);
+
+                strategy
+                    .volatility_range_spec(tick_index_current, realized_volatility_ticks)
+                    .unwrap_or(range_spec)
+            }
+            _ => strategy
+                .range_spec(Clock::get()?.unix_timestamp, last_rebalance_timestamp)
+                .unwrap_or(range_spec),
+        }
+    };
+    msg!("strategy: {:?}, range_spec: {:?}", strategy, range_spec);
+
+    // whirlpool only accepts ticks on the pool's tick_spacing
+    let (lower_tick_index, upper_tick_index) =
+        range_spec.to_tick_range(ctx.accounts.pool.sqrt_price, ctx.accounts.pool.tick_spacing)?;
+    msg!(
+        "lower_tick_index: {}, upper_tick_index: {}",
+        lower_tick_index,
+        upper_tick_index
+    );
+
+    check_tick_array(
+        &ctx.accounts.old_tick_array_lower.to_account_info(),
+        &ctx.accounts.pool,
+        tick_index_lower,
+        BloomErrorCode::InvalidTickArrayLower,
+    )?;
+    check_tick_array(
+        &ctx.accounts.old_tick_array_upper.to_account_info(),
+        &ctx.accounts.pool,
+        tick_index_upper,
+        BloomErrorCode::InvalidTickArrayUpper,
+    )?;
+    check_tick_array(
+        &ctx.accounts.new_tick_array_lower.to_account_info(),
+        &ctx.accounts.pool,
+        lower_tick_index,
+        BloomErrorCode::InvalidTickArrayLower,
+    )?;
+    check_tick_array(
+        &ctx.accounts.new_tick_array_upper.to_account_info(),
+        &ctx.accounts.pool,
+        upper_tick_index,
+        BloomErrorCode::InvalidTickArrayUpper,
+    )?;
     // TODO: collect rewards
 
     msg!(
@@ -196,6 +354,10 @@ pub fn handler(
         old_pool_position.reward_infos
     );
 
+    // decrease_liquidity settles the fees owed, collect_fees pays out exactly this
+    let fee_a = old_pool_position.fee_owed_a;
+    let fee_b = old_pool_position.fee_owed_b;
+
     // collect fees
     let collect_fees_accounts = CollectFees {
         whirlpool: ctx.accounts.pool.to_account_info(),
@@ -221,6 +383,13 @@ pub fn handler(
         ]],
     ))?;
 
+    emit!(FeesCollected {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        pool_position: ctx.accounts.old_pool_position.key(),
+        fee_a,
+        fee_b,
+    });
+
     ctx.accounts.token_a_vault.reload().unwrap();
     ctx.accounts.token_b_vault.reload().unwrap();
 
@@ -277,7 +446,11 @@ pub fn handler(
             open_position_accounts,
             &[&[
                 b"pool_position_mint",
-                new_pool_position_mint_seed.as_bytes(),
+                ctx.accounts
+                    .vault_manager
+                    .next_position_nonce()
+                    .to_le_bytes()
+                    .as_ref(),
                 ctx.accounts.pool.key().as_ref(),
                 &[*ctx.bumps.get("new_pool_position_mint").unwrap()],
             ]],
@@ -292,109 +465,74 @@ pub fn handler(
     let new_pool_position =
         &mut WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.new_pool_position)?;
 
-    // check that our position is within range of the pool
-    if tick_index_current < new_pool_position.tick_lower_index
-        || tick_index_current >= new_pool_position.tick_upper_index
+    // check that our position is within range of the pool, a range order sits next to it
+    if !mode.is_range_order()
+        && (tick_index_current < new_pool_position.tick_lower_index
+            || tick_index_current >= new_pool_position.tick_upper_index)
     {
         return Err(error!(BloomErrorCode::PositionOutOfRange));
     };
 
-    // calculate deposit ratio of position
-    let lower_price = math::sqrt_price_x64_to_price(
-        sqrt_price_from_tick_index(new_pool_position.tick_lower_index),
-        ctx.accounts.token_a.decimals,
-        ctx.accounts.token_b.decimals,
-    );
-    let upper_price = math::sqrt_price_x64_to_price(
-        sqrt_price_from_tick_index(new_pool_position.tick_upper_index),
-        ctx.accounts.token_a.decimals,
-        ctx.accounts.token_b.decimals,
-    );
-    let current_price = math::sqrt_price_x64_to_price(
-        ctx.accounts.pool.sqrt_price,
-        ctx.accounts.token_a.decimals,
-        ctx.accounts.token_b.decimals,
-    );
-    let (token_a_percentage, token_b_percentage) =
-        math::calculate_deposit_ratio(lower_price, current_price, upper_price);
-    msg!(
-        "deposit_ratio\tA: {}% B: {}%",
-        token_a_percentage,
-        token_b_percentage
-    );
-
-    // determine which token we need more of
-    let token_a_amount = math::amount_to_ui_amount(
-        ctx.accounts.token_a_vault.amount,
-        ctx.accounts.token_a.decimals,
-    );
-    let token_b_amount = math::amount_to_ui_amount(
-        ctx.accounts.token_b_vault.amount,
-        ctx.accounts.token_b.decimals,
-    );
-    let token_b_leftover_amount = math::calculate_token_b_leftover(
-        token_a_percentage,
-        token_b_percentage,
-        token_a_amount,
-        token_b_amount,
-        current_price,
-    );
-    let token_a_leftover_amount = math::calculate_token_a_leftover(
-        token_a_percentage,
-        token_b_percentage,
-        token_a_amount,
-        token_b_amount,
-        current_price,
-    );
+    let sqrt_price_before_swap = ctx.accounts.pool.sqrt_price;
 
-    if token_b_leftover_amount > 0.0 {
-        msg!("need to swap token B for token A");
-        let token_b_max_out = math::ui_amount_to_amount(
-            math::calculate_token_b_swap_amount(token_b_percentage, token_b_leftover_amount),
+    // a range order deposits the filled side as is, everything else swaps to the deposit ratio
+    let (a_to_b, swap_amount_in, swap_amount_out) = if mode.is_range_order() {
+        (false, 0, 0)
+    } else {
+        // calculate deposit ratio of position
+        let lower_price = math::sqrt_price_x64_to_price(
+            sqrt_price_from_tick_index(new_pool_position.tick_lower_index),
+            ctx.accounts.token_a.decimals,
             ctx.accounts.token_b.decimals,
         );
-        msg!("token_b_max_out: {}", token_b_max_out);
-
-        let a_to_b = false;
-
-        let swap_accounts = Swap {
-            token_program: ctx.accounts.token_program.to_account_info(),
-            token_authority: ctx.accounts.vault_manager.to_account_info(),
-            whirlpool: ctx.accounts.pool.to_account_info(),
-            token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
-            token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
-            token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
-            token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
-            tick_array0: ctx.accounts.tick_array_current.to_account_info(),
-            tick_array1: ctx.accounts.tick_array_current.to_account_info(),
-            tick_array2: ctx.accounts.tick_array_current.to_account_info(),
-            oracle: ctx.accounts.oracle.to_account_info(),
-        };
+        let upper_price = math::sqrt_price_x64_to_price(
+            sqrt_price_from_tick_index(new_pool_position.tick_upper_index),
+            ctx.accounts.token_a.decimals,
+            ctx.accounts.token_b.decimals,
+        );
+        let current_price = math::sqrt_price_x64_to_price(
+            ctx.accounts.pool.sqrt_price,
+            ctx.accounts.token_a.decimals,
+            ctx.accounts.token_b.decimals,
+        );
+        let (a_to_b, swap_amount) = math::calculate_rebalance_swap(
+            lower_price,
+            current_price,
+            upper_price,
+            ctx.accounts.token_a_vault.amount,
+            ctx.accounts.token_b_vault.amount,
+            ctx.accounts.token_a.decimals,
+            ctx.accounts.token_b.decimals,
+        );
+        msg!("swap a_to_b: {}, swap_amount: {}", a_to_b, swap_amount);
+
+        check_swap_tick_arrays(
+            [
+                &ctx.accounts.tick_array_current.to_account_info(),
+                &ctx.accounts.tick_array_1.to_account_info(),
+                &ctx.accounts.tick_array_2.to_account_info(),
+            ],
+            &ctx.accounts.pool,
+            a_to_b,
+        )?;
 
-        swap(
-            CpiContext::new_with_signer(
-                ctx.accounts.whirlpool_program.to_account_info(),
-                swap_accounts,
-                &[&[
-                    ctx.accounts.pool.key().as_ref(),
-                    &[*ctx.bumps.get("vault_manager").unwrap()],
-                ]],
-            ),
-            token_b_max_out,
-            0,
-            MAX_SQRT_PRICE_X64,
-            true,
+        let min_amount_out = rebalance_swap_min_output(
+            &*ctx.accounts.price_observations.load()?,
+            &ctx.accounts.pool,
+            Clock::get()?.unix_timestamp,
+            swap_amount,
             a_to_b,
         )?;
-    } else {
-        msg!("need to swap token A for token B");
-        let token_a_max_out = math::ui_amount_to_amount(
-            math::calculate_token_a_swap_amount(token_a_percentage, token_a_leftover_amount),
-            ctx.accounts.token_a.decimals,
-        );
-        msg!("token_a_max_out: {}", token_a_max_out);
+        msg!("min_amount_out: {}", min_amount_out);
+
+        let sqrt_price_limit = if a_to_b {
+            MIN_SQRT_PRICE_X64
+        } else {
+            MAX_SQRT_PRICE_X64
+        };
 
-        let a_to_b = true;
+        let token_a_before_swap = ctx.accounts.token_a_vault.amount;
+        let token_b_before_swap = ctx.accounts.token_b_vault.amount;
 
         let swap_accounts = Swap {
             token_program: ctx.accounts.token_program.to_account_info(),
@@ -405,8 +543,8 @@ pub fn handler(
             token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
             token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
             tick_array0: ctx.accounts.tick_array_current.to_account_info(),
-            tick_array1: ctx.accounts.tick_array_current.to_account_info(),
-            tick_array2: ctx.accounts.tick_array_current.to_account_info(),
+            tick_array1: ctx.accounts.tick_array_1.to_account_info(),
+            tick_array2: ctx.accounts.tick_array_2.to_account_info(),
             oracle: ctx.accounts.oracle.to_account_info(),
         };
 
@@ -419,34 +557,75 @@ pub fn handler(
                     &[*ctx.bumps.get("vault_manager").unwrap()],
                 ]],
             ),
-            token_a_max_out,
-            0,
-            MIN_SQRT_PRICE_X64,
+            swap_amount,
+            min_amount_out,
+            sqrt_price_limit,
             true,
             a_to_b,
         )?;
-    }
 
-    // reload to get correct amounts after swap
-    ctx.accounts.token_a_vault.reload().unwrap();
-    ctx.accounts.token_b_vault.reload().unwrap();
-    ctx.accounts.pool.reload().unwrap();
+        // reload to get correct amounts after swap
+        ctx.accounts.token_a_vault.reload().unwrap();
+        ctx.accounts.token_b_vault.reload().unwrap();
+        ctx.accounts.pool.reload().unwrap();
 
-    msg!(
-        "after_swap:\ttoken_a_vault: {}, token_b_vault: {}",
-        ctx.accounts.token_a_vault.amount,
-        ctx.accounts.token_b_vault.amount
-    );
+        msg!(
+            "after_swap:\ttoken_a_vault: {}, token_b_vault: {}",
+            ctx.accounts.token_a_vault.amount,
+            ctx.accounts.token_b_vault.amount
+        );
+
+        let (swap_amount_in, swap_amount_out) = if a_to_b {
+            (
+                token_a_before_swap - ctx.accounts.token_a_vault.amount,
+                ctx.accounts.token_b_vault.amount - token_b_before_swap,
+            )
+        } else {
+            (
+                token_b_before_swap - ctx.accounts.token_b_vault.amount,
+                ctx.accounts.token_a_vault.amount - token_a_before_swap,
+            )
+        };
+
+        (a_to_b, swap_amount_in, swap_amount_out)
+    };
+
+    // the swap can move the price, deposit at the price it left behind even if that is outside
+    // the new range. get_liquidity_for_amounts falls back to the single token the range needs
+    let tick_index_current = ctx.accounts.pool.tick_current_index;
 
     let sqrt_price_current_x64 = ctx.accounts.pool.sqrt_price;
+    let sqrt_price_lower_x64 = sqrt_price_from_tick_index(new_pool_position.tick_lower_index);
     let sqrt_price_upper_x64 = sqrt_price_from_tick_index(new_pool_position.tick_upper_index);
 
-    let liquidity = math::get_liquidity_from_token_a(
+    // the swap only approximates the deposit ratio, so deposit the most liquidity both
+    // balances can support and leave the rest idle for the next compound
+    let liquidity = math::get_liquidity_for_amounts(
         ctx.accounts.token_a_vault.amount as u128,
+        ctx.accounts.token_b_vault.amount as u128,
+        sqrt_price_lower_x64,
         sqrt_price_current_x64,
         sqrt_price_upper_x64,
     );
 
+    let (token_a_needed, token_b_needed) = calculate_liquidity_token_deltas(
+        tick_index_current,
+        sqrt_price_current_x64,
+        &new_pool_position,
+        liquidity as i128,
+    )
+    .unwrap();
+
+    // the token deltas round up and can ask for a unit more than the vault holds, scale the
+    // liquidity down to what the balances support instead of failing the rebalance
+    let liquidity = math::scale_liquidity_to_balances(
+        liquidity,
+        token_a_needed,
+        token_b_needed,
+        ctx.accounts.token_a_vault.amount,
+        ctx.accounts.token_b_vault.amount,
+    );
+
     let (token_max_a, token_max_b) = calculate_liquidity_token_deltas(
         tick_index_current,
         sqrt_price_current_x64,
@@ -454,6 +633,8 @@ pub fn handler(
         liquidity as i128,
     )
     .unwrap();
+    let token_max_a = token_max_a.min(ctx.accounts.token_a_vault.amount);
+    let token_max_b = token_max_b.min(ctx.accounts.token_b_vault.amount);
 
     msg!(
         "liquidity: {}, token_max_a: {}, token_max_b: {}",
@@ -462,16 +643,6 @@ pub fn handler(
         token_max_b,
     );
 
-    if token_max_b > ctx.accounts.token_b_vault.amount {
-        msg!("not enough of token_b in vault");
-        return Err(error!(BloomErrorCode::Miscalculation));
-    };
-
-    if token_max_a > ctx.accounts.token_a_vault.amount {
-        msg!("not enough of token_a in vault");
-        return Err(error!(BloomErrorCode::Miscalculation));
-    }
-
     // add liquidity back into the new position
     let increase_liquidity_accounts = IncreaseLiquidity {
         whirlpool: ctx.accounts.pool.to_account_info(),
@@ -513,16 +684,104 @@ pub fn handler(
         ctx.accounts.token_b_vault.amount
     );
 
+    // whatever did not fit the new range stays in the vault token accounts
+    emit!(Rebalanced {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        old_tick_lower_index: tick_index_lower,
+        old_tick_upper_index: tick_index_upper,
+        new_tick_lower_index: new_pool_position.tick_lower_index,
+        new_tick_upper_index: new_pool_position.tick_upper_index,
+        new_price_lower_x64: math::tick_index_to_price_x64(new_pool_position.tick_lower_index),
+        new_price_upper_x64: math::tick_index_to_price_x64(new_pool_position.tick_upper_index),
+        range_spec,
+        sqrt_price_x64: sqrt_price_current_x64,
+        a_to_b,
+        swap_amount_in,
+        swap_amount_out,
+        fee_a,
+        fee_b,
+        liquidity,
+        token_a_leftover: ctx.accounts.token_a_vault.amount,
+        token_b_leftover: ctx.accounts.token_b_vault.amount,
+    });
+
+    let clock = Clock::get()?;
+
+    ctx.accounts
+        .rebalance_history
+        .load_mut()?
+        .append(RebalanceEntry {
+            slot: clock.slot,
+            old_tick_lower_index: tick_index_lower,
+            old_tick_upper_index: tick_index_upper,
+            new_tick_lower_index: new_pool_position.tick_lower_index,
+            new_tick_upper_index: new_pool_position.tick_upper_index,
+            sqrt_price_before_x64: sqrt_price_before_swap,
+            sqrt_price_after_x64: sqrt_price_current_x64,
+            a_to_b: a_to_b as u8,
+            swap_amount_in,
+            swap_amount_out,
+            token_a_leftover: ctx.accounts.token_a_vault.amount,
+            token_b_leftover: ctx.accounts.token_b_vault.amount,
+        });
+
+    let now = clock.unix_timestamp;
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.update_time(
+        now,
+        position_in_range(
+            tick_index_current,
+            new_pool_position.tick_lower_index,
+            new_pool_position.tick_upper_index,
+        ),
+    );
+    vault_stats.fees_earned_a += fee_a;
+    vault_stats.fees_earned_b += fee_b;
+    vault_stats.record_swap(
+        a_to_b,
+        swap_amount_in,
+        math::calculate_swap_cost(
+            swap_amount_in,
+            swap_amount_out,
+            sqrt_price_before_swap,
+            a_to_b,
+        ),
+    );
+    vault_stats.update_share_price(
+        liquidity,
+        new_pool_position.tick_lower_index,
+        new_pool_position.tick_upper_index,
+        sqrt_price_current_x64,
+        ctx.accounts.pool_token.supply,
+    );
+    vault_stats.rebalance_count += 1;
+    vault_stats.last_rebalance_timestamp = now;
+
     // update vault_manager fields with new positions
     let vault_manager = &mut ctx.accounts.vault_manager;
     vault_manager.pool_position = ctx.accounts.new_pool_position.key();
     vault_manager.pool_position_mint = ctx.accounts.new_pool_position_mint.key();
     vault_manager.pool_position_token_account = ctx.accounts.new_pool_position_token_account.key();
+    vault_manager.position_nonce += 1;
+
+    if let VaultMode::RangeOrder { sell_a } = mode {
+        vault_manager.mode = VaultMode::RangeOrder { sell_a: !sell_a };
+
+        emit!(RangeOrderFilled {
+            vault_manager: vault_manager.key(),
+            sell_a,
+            filled_tick_lower_index: tick_index_lower,
+            filled_tick_upper_index: tick_index_upper,
+            next_tick_lower_index: new_pool_position.tick_lower_index,
+            next_tick_upper_index: new_pool_position.tick_upper_index,
+            liquidity,
+        });
+    }
 
     Ok(())
 }
 
-fn position_in_range(
+pub fn position_in_range(
     tick_index_current: i32,
     tick_index_lower: i32,
     tick_index_upper: i32,
@@ -534,9 +793,161 @@ fn position_in_range(
     return true;
 }
 
-#[event]
-pub struct PositionInRange {
-    pub lower_price: f64,
-    pub current_price: f64,
-    pub upper_price: f64,
+// the whirlpool program only rejects a wrong tick array deep inside its own checks,
+// compare against the expected PDA up front so the failure names the account
+pub fn check_tick_array(
+    tick_array: &AccountInfo,
+    pool: &Account<Whirlpool>,
+    tick_index: i32,
+    error_code: BloomErrorCode,
+) -> Result<()> {
+    let start_tick_index = math::get_tick_array_start_index(tick_index, pool.tick_spacing);
+    let (tick_array_address, _bump) = Pubkey::find_program_address(
+        &[
+            b"tick_array",
+            pool.key().as_ref(),
+            start_tick_index.to_string().as_bytes(),
+        ],
+        &whirlpools::ID,
+    );
+
+    if tick_array.key() != tick_array_address {
+        msg!(
+            "expected tick_array: {}, start_tick_index: {}",
+            tick_array_address,
+            start_tick_index
+        );
+        return Err(error!(error_code));
+    }
+
+    Ok(())
+}
+
+// a swap can cross into the next two tick arrays in its direction, near the ends of the tick
+// range the last array is repeated the way the whirlpool sdk does
+pub fn check_swap_tick_arrays(
+    tick_arrays: [&AccountInfo; 3],
+    pool: &Account<Whirlpool>,
+    a_to_b: bool,
+) -> Result<()> {
+    let ticks_in_array = math::TICK_ARRAY_SIZE * pool.tick_spacing as i32;
+
+    // a b to a swap starts from the array holding the next initializable tick
+    let (tick_index_start, step) = if a_to_b {
+        (pool.tick_current_index, -ticks_in_array)
+    } else {
+        (
+            pool.tick_current_index + pool.tick_spacing as i32,
+            ticks_in_array,
+        )
+    };
+
+    for (i, tick_array) in tick_arrays.into_iter().enumerate() {
+        let tick_index =
+            (tick_index_start + step * i as i32).clamp(math::MIN_TICK_INDEX, math::MAX_TICK_INDEX);
+        let error_code = if i == 0 {
+            BloomErrorCode::InvalidTickArrayCurrent
+        } else {
+            BloomErrorCode::InvalidTickArraySwap
+        };
+        check_tick_array(tick_array, pool, tick_index, error_code)?;
+    }
+
+    Ok(())
+}
+
+// slippage a permissionless rebalance swap may take on top of the pool fee, measured against the
+// time weighted price so a crank sandwiched by a price move can not fill at the moved price
+pub const MAX_REBALANCE_SWAP_SLIPPAGE_BPS: u16 = 100;
+
+// rebalance swaps wait while the pool price is further from the time weighted price than they may
+// slip, or there are too few observations to tell. the crank returns Ok instead of failing so the
+// sample it recorded stays and the average can catch up with a real move
+pub fn rebalance_price_settled(
+    price_observations: &PriceObservations,
+    tick_index_current: i32,
+    now: i64,
+) -> bool {
+    match price_observations.time_weighted_tick_index(now) {
+        Some(reference_tick_index) => {
+            (tick_index_current - reference_tick_index).abs()
+                <= math::bps_to_tick_delta(MAX_REBALANCE_SWAP_SLIPPAGE_BPS)
+        }
+        None => false,
+    }
+}
+
+// least output a rebalance swap accepts, fails without enough observations to price it
+pub fn rebalance_swap_min_output(
+    price_observations: &PriceObservations,
+    pool: &Whirlpool,
+    now: i64,
+    amount_in: u64,
+    a_to_b: bool,
+) -> Result<u64> {
+    let reference_tick_index = price_observations
+        .time_weighted_tick_index(now)
+        .ok_or_else(|| error!(BloomErrorCode::InsufficientPriceObservations))?;
+
+    Ok(math::calculate_min_swap_output(
+        amount_in,
+        math::tick_index_to_sqrt_price_x64(reference_tick_index),
+        a_to_b,
+        pool.fee_rate,
+        MAX_REBALANCE_SWAP_SLIPPAGE_BPS,
+    ))
+}
+
+// asks a Strategy::External program for the next range, the program is the first remaining account
+pub fn get_external_tick_range<'info>(
+    strategy_program: Pubkey,
+    pool: AccountInfo<'info>,
+    vault_manager: AccountInfo<'info>,
+    vault_stats: AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<(i32, i32)> {
+    let strategy_program_info = remaining_accounts
+        .first()
+        .ok_or_else(|| error!(BloomErrorCode::InvalidStrategyProgram))?;
+    if strategy_program_info.key() != strategy_program || !strategy_program_info.executable {
+        return Err(error!(BloomErrorCode::InvalidStrategyProgram));
+    }
+
+    // anchor sighash, so a strategy can be an anchor program with a get_target_range instruction
+    let get_target_range_ix = Instruction {
+        program_id: strategy_program,
+        accounts: vec![
+            AccountMeta::new_readonly(pool.key(), false),
+            AccountMeta::new_readonly(vault_manager.key(), false),
+            AccountMeta::new_readonly(vault_stats.key(), false),
+        ],
+        data: hash(b"global:get_target_range").to_bytes()[..8].to_vec(),
+    };
+
+    invoke(
+        &get_target_range_ix,
+        &[
+            pool,
+            vault_manager,
+            vault_stats,
+            strategy_program_info.clone(),
+        ],
+    )?;
+
+    let (return_program, return_data) =
+        get_return_data().ok_or_else(|| error!(BloomErrorCode::MissingStrategyReturnData))?;
+    if return_program != strategy_program {
+        return Err(error!(BloomErrorCode::MissingStrategyReturnData));
+    }
+
+    let (lower_tick_index, upper_tick_index) = <(i32, i32)>::try_from_slice(&return_data)
+        .map_err(|_| error!(BloomErrorCode::MissingStrategyReturnData))?;
+    msg!(
+        "strategy_program: {}, lower_tick_index: {}, upper_tick_index: {}",
+        strategy_program,
+        lower_tick_index,
+        upper_tick_index
+    );
+
+    Ok((lower_tick_index, upper_tick_index))
 }
@@ -0,0 +1,235 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token;
+use whirlpool::math::{sqrt_price_from_tick_index, MAX_SQRT_PRICE_X64, MIN_SQRT_PRICE_X64};
+use whirlpools::cpi::{accounts::Swap, swap};
+use whirlpools::program::Whirlpool as WhirlpoolProgram;
+use whirlpools::state::{TickArray, Whirlpool};
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::RebalanceSwapped;
+use crate::math;
+
+#[derive(Accounts)]
+pub struct RebalanceSwap<'info> {
+    #[account(mut,
+        seeds = [pool.key().as_ref()], bump,
+        has_one = token_a,
+        has_one = token_b,
+        has_one = token_a_vault,
+        has_one = token_b_vault,
+        has_one = pool,
+        has_one = token_a_pool_vault,
+        has_one = token_b_pool_vault,
+        constraint = vault_manager.rebalance_state == RebalanceState::Withdrawn @ BloomErrorCode::InvalidRebalanceState)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(mut, seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
+    #[account(mut, seeds = [b"rebalance_history", vault_manager.key().as_ref()], bump)]
+    pub rebalance_history: AccountLoader<'info, RebalanceHistory>,
+
+    #[account(mut, seeds = [b"price_observations", vault_manager.key().as_ref()], bump)]
+    pub price_observations: AccountLoader<'info, PriceObservations>,
+
+    pub token_a: Box<Account<'info, token::Mint>>,
+
+    pub token_b: Box<Account<'info, token::Mint>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_a.key().as_ref()], bump, token::mint = token_a, token::authority = vault_manager)]
+    pub token_a_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_b.key().as_ref()], bump, token::mint = token_b, token::authority = vault_manager)]
+    pub token_b_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    #[account(mut, token::mint = token_a)]
+    pub token_a_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, token::mint = token_b)]
+    pub token_b_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub tick_array_current: AccountLoader<'info, TickArray>,
+
+    // the next two tick arrays in the direction of the swap
+    #[account(mut)]
+    pub tick_array_1: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_2: AccountLoader<'info, TickArray>,
+
+    /// CHECK: Must be provided for swapping but not currently used by Whirlpool Program
+    #[account(seeds = [b"oracle", pool.key().as_ref()], bump, seeds::program = whirlpool_program)]
+    pub oracle: UncheckedAccount<'info>,
+
+    pub crank: Signer<'info>,
+
+    pub token_program: Program<'info, token::Token>,
+    pub whirlpool_program: Program<'info, WhirlpoolProgram>,
+}
+
+// second step of a rebalance: swap the withdrawn tokens into the deposit ratio of the target range
+pub fn handler(ctx: Context<RebalanceSwap>) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+
+    // a sample taken at now has no weight in the time weighted price the swap is checked against
+    ctx.accounts
+        .price_observations
+        .load_mut()?
+        .record(now, ctx.accounts.pool.tick_current_index);
+
+    if !rebalance_price_settled(
+        &*ctx.accounts.price_observations.load()?,
+        ctx.accounts.pool.tick_current_index,
+        now,
+    ) {
+        msg!("pool price away from the time weighted price, retry the swap later");
+        return Ok(());
+    }
+
+    let lower_price = math::sqrt_price_x64_to_price(
+        sqrt_price_from_tick_index(ctx.accounts.vault_manager.rebalance_lower_tick_index),
+        ctx.accounts.token_a.decimals,
+        ctx.accounts.token_b.decimals,
+    );
+    let upper_price = math::sqrt_price_x64_to_price(
+        sqrt_price_from_tick_index(ctx.accounts.vault_manager.rebalance_upper_tick_index),
+        ctx.accounts.token_a.decimals,
+        ctx.accounts.token_b.decimals,
+    );
+    let current_price = math::sqrt_price_x64_to_price(
+        ctx.accounts.pool.sqrt_price,
+        ctx.accounts.token_a.decimals,
+        ctx.accounts.token_b.decimals,
+    );
+
+    let (a_to_b, swap_amount) = math::calculate_rebalance_swap(
+        lower_price,
+        current_price,
+        upper_price,
+        ctx.accounts.token_a_vault.amount,
+        ctx.accounts.token_b_vault.amount,
+        ctx.accounts.token_a.decimals,
+        ctx.accounts.token_b.decimals,
+    );
+    msg!("swap a_to_b: {}, swap_amount: {}", a_to_b, swap_amount);
+
+    check_swap_tick_arrays(
+        [
+            &ctx.accounts.tick_array_current.to_account_info(),
+            &ctx.accounts.tick_array_1.to_account_info(),
+            &ctx.accounts.tick_array_2.to_account_info(),
+        ],
+        &ctx.accounts.pool,
+        a_to_b,
+    )?;
+
+    let min_amount_out = rebalance_swap_min_output(
+        &*ctx.accounts.price_observations.load()?,
+        &ctx.accounts.pool,
+        now,
+        swap_amount,
+        a_to_b,
+    )?;
+    msg!("min_amount_out: {}", min_amount_out);
+
+    let sqrt_price_limit = if a_to_b {
+        MIN_SQRT_PRICE_X64
+    } else {
+        MAX_SQRT_PRICE_X64
+    };
+
+    let token_a_vault_before = ctx.accounts.token_a_vault.amount;
+    let token_b_vault_before = ctx.accounts.token_b_vault.amount;
+    let sqrt_price_before_swap = ctx.accounts.pool.sqrt_price;
+
+    let swap_accounts = Swap {
+        token_program: ctx.accounts.token_program.to_account_info(),
+        token_authority: ctx.accounts.vault_manager.to_account_info(),
+        whirlpool: ctx.accounts.pool.to_account_info(),
+        token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
+        token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
+        token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
+        token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
+        tick_array0: ctx.accounts.tick_array_current.to_account_info(),
+        tick_array1: ctx.accounts.tick_array_1.to_account_info(),
+        tick_array2: ctx.accounts.tick_array_2.to_account_info(),
+        oracle: ctx.accounts.oracle.to_account_info(),
+    };
+
+    swap(
+        CpiContext::new_with_signer(
+            ctx.accounts.whirlpool_program.to_account_info(),
+            swap_accounts,
+            &[&[
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("vault_manager").unwrap()],
+            ]],
+        ),
+        swap_amount,
+        min_amount_out,
+        sqrt_price_limit,
+        true,
+        a_to_b,
+    )?;
+
+    ctx.accounts.token_a_vault.reload()?;
+    ctx.accounts.token_b_vault.reload()?;
+    ctx.accounts.pool.reload()?;
+
+    msg!(
+        "after rebalance_swap\ttoken_a_vault: {}, token_b_vault: {}",
+        ctx.accounts.token_a_vault.amount,
+        ctx.accounts.token_b_vault.amount
+    );
+
+    let (swap_amount_in, swap_amount_out) = if a_to_b {
+        (
+            token_a_vault_before - ctx.accounts.token_a_vault.amount,
+            ctx.accounts.token_b_vault.amount - token_b_vault_before,
+        )
+    } else {
+        (
+            token_b_vault_before - ctx.accounts.token_b_vault.amount,
+            ctx.accounts.token_a_vault.amount - token_a_vault_before,
+        )
+    };
+
+    let swap_cost = math::calculate_swap_cost(
+        swap_amount_in,
+        swap_amount_out,
+        sqrt_price_before_swap,
+        a_to_b,
+    );
+
+    emit!(RebalanceSwapped {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        sqrt_price_before_x64: sqrt_price_before_swap,
+        sqrt_price_x64: ctx.accounts.pool.sqrt_price,
+        a_to_b,
+        swap_amount_in,
+        swap_amount_out,
+        min_amount_out,
+        swap_cost,
+    });
+
+    // a retargeted rebalance swaps again, its entry keeps the swap that set the final ratio
+    let mut rebalance_history = ctx.accounts.rebalance_history.load_mut()?;
+    let entry = rebalance_history.pending_mut();
+    entry.sqrt_price_before_x64 = sqrt_price_before_swap;
+    entry.a_to_b = a_to_b as u8;
+    entry.swap_amount_in = swap_amount_in;
+    entry.swap_amount_out = swap_amount_out;
+
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.update_time(now, false);
+    vault_stats.record_swap(a_to_b, swap_amount_in, swap_cost);
+
+    ctx.accounts.vault_manager.rebalance_state = RebalanceState::Swapped;
+
+    Ok(())
+}
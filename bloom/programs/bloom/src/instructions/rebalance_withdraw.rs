@@ -0,0 +1,380 @@
+use anchor_lang::prelude::*;
+use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
+use anchor_spl::token;
+use whirlpool::state::Position as WhirlpoolPosition;
+use whirlpools::cpi::{
+    accounts::{ClosePosition, CollectFees, DecreaseLiquidity},
+    {close_position, collect_fees, decrease_liquidity},
+};
+use whirlpools::program::Whirlpool as WhirlpoolProgram;
+use whirlpools::state::{TickArray, Whirlpool};
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::{FeesCollected, PositionInRange, RebalanceWithdrawn};
+use crate::math;
+use crate::strategy::Strategy;
+
+#[derive(Accounts)]
+pub struct RebalanceWithdraw<'info> {
+    #[account(mut,
+        seeds = [pool.key().as_ref()], bump,
+        has_one = token_a,
+        has_one = token_b,
+        has_one = token_a_vault,
+        has_one = token_b_vault,
+        has_one = pool,
+        has_one = pool_token,
+        has_one = token_a_pool_vault,
+        has_one = token_b_pool_vault,
+        has_one = pool_position,
+        constraint = !vault_manager.rebalance_state.in_progress() @ BloomErrorCode::RebalanceInProgress,
+        // range orders are harvested by rebalance_positions, they never swap
+        constraint = !vault_manager.mode.is_range_order() @ BloomErrorCode::InvalidVaultMode)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(mut, seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
+    #[account(mut, seeds = [b"rebalance_history", vault_manager.key().as_ref()], bump)]
+    pub rebalance_history: AccountLoader<'info, RebalanceHistory>,
+
+    #[account(mut, seeds = [b"price_observations", vault_manager.key().as_ref()], bump)]
+    pub price_observations: AccountLoader<'info, PriceObservations>,
+
+    pub token_a: Box<Account<'info, token::Mint>>,
+
+    pub token_b: Box<Account<'info, token::Mint>>,
+
+    #[account(seeds = [b"pool_token", vault_manager.key().as_ref()], bump)]
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_a.key().as_ref()], bump, token::mint = token_a, token::authority = vault_manager)]
+    pub token_a_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_b.key().as_ref()], bump, token::mint = token_b, token::authority = vault_manager)]
+    pub token_b_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    #[account(mut, token::mint = token_a)]
+    pub token_a_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, token::mint = token_b)]
+    pub token_b_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    /// CHECK: deserialized inside instruction
+    #[account(mut, seeds = [b"position", pool_position_mint.key().as_ref()], bump, seeds::program = whirlpool_program)]
+    pub pool_position: UncheckedAccount<'info>,
+
+    #[account(mut, address = vault_manager.pool_position_mint)]
+    pub pool_position_mint: Box<Account<'info, token::Mint>>,
+
+    #[account(mut, token::mint = pool_position_mint, token::authority = vault_manager)]
+    pub pool_position_token_account: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub tick_array_lower: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_upper: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub crank: Signer<'info>,
+
+    pub token_program: Program<'info, token::Token>,
+    pub whirlpool_program: Program<'info, WhirlpoolProgram>,
+}
+
+// first step of a rebalance: pull all liquidity and fees into the vault and close the position
+pub fn handler(
+    ctx: Context<RebalanceWithdraw>,
+    lower_tick_index: i32,
+    upper_tick_index: i32,
+) -> Result<()> {
+    // Have to do this hacky thing because of anchor-lang version mismatch
+    let pool_position =
+        &mut WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
+
+    let tick_index_current = ctx.accounts.pool.tick_current_index;
+
+    // sample on every crank, Strategy::Volatility needs prices from calm periods too
+    ctx.accounts
+        .price_observations
+        .load_mut()?
+        .record(Clock::get()?.unix_timestamp, tick_index_current);
+
+    // nothing to do while the current position is still earning fees
+    if position_in_range(
+        tick_index_current,
+        pool_position.tick_lower_index,
+        pool_position.tick_upper_index,
+    ) {
+        msg!("position in range, no rebalance");
+        emit!(PositionInRange {
+            vault_manager: ctx.accounts.vault_manager.key(),
+            tick_lower_index: pool_position.tick_lower_index,
+            tick_upper_index: pool_position.tick_upper_index,
+            tick_current_index: tick_index_current,
+            sqrt_price_x64: ctx.accounts.pool.sqrt_price,
+        });
+
+        let vault_stats = &mut ctx.accounts.vault_stats;
+        vault_stats.update_time(Clock::get()?.unix_timestamp, true);
+        vault_stats.update_share_price(
+            pool_position.liquidity,
+            pool_position.tick_lower_index,
+            pool_position.tick_upper_index,
+            ctx.accounts.pool.sqrt_price,
+            ctx.accounts.pool_token.supply,
+        );
+        return Ok(());
+    }
+
+    let strategy = ctx.accounts.vault_manager.strategy;
+
+    // trailing strategies wait out moves against their direction
+    if !strategy.should_rebalance(
+        &ctx.accounts.pool,
+        pool_position.tick_lower_index,
+        pool_position.tick_upper_index,
+    ) {
+        msg!("strategy holding position, no rebalance");
+        ctx.accounts
+            .vault_stats
+            .update_time(Clock::get()?.unix_timestamp, false);
+        return Ok(());
+    }
+
+    // the swap that follows would wait for the price to settle, keep earning fees until then
+    if !rebalance_price_settled(
+        &*ctx.accounts.price_observations.load()?,
+        tick_index_current,
+        Clock::get()?.unix_timestamp,
+    ) {
+        msg!("pool price away from the time weighted price, no rebalance");
+        ctx.accounts
+            .vault_stats
+            .update_time(Clock::get()?.unix_timestamp, false);
+        return Ok(());
+    }
+
+    // the crank's range is only used by Strategy::Manual vaults
+    let range_spec = match strategy {
+        Strategy::External { program, .. } => {
+            let (lower_tick_index, upper_tick_index) = get_external_tick_range(
+                program,
+                ctx.accounts.pool.to_account_info(),
+                ctx.accounts.vault_manager.to_account_info(),
+                ctx.accounts.vault_stats.to_account_info(),
+                ctx.remaining_accounts,
+            )?;
+            let reference_tick_index = ctx
+                .accounts
+                .price_observations
+                .load()?
+                .reference_tick_index(Clock::get()?.unix_timestamp)
+                .ok_or_else(|| error!(BloomErrorCode::InsufficientPriceObservations))?;
+            strategy.check_external_tick_range(
+                ctx.accounts.pool.tick_spacing,
+                reference_tick_index,
+                lower_tick_index,
+                upper_tick_index,
+            )?;
+
+            RangeSpec::Ticks {
+                lower_tick_index,
+                upper_tick_index,
+            }
+        }
+        Strategy::Volatility { .. } => {
+            let realized_volatility_ticks = ctx
+                .accounts
+                .price_observations
+                .load()?
+                .realized_volatility_ticks(Clock::get()?.unix_timestamp);
+            msg!("realized_volatility_ticks: {}", realized_volatility_ticks);
+
+            strategy
+                .volatility_range_spec(tick_index_current, realized_volatility_ticks)
+                .unwrap_or(RangeSpec::Ticks {
+                    lower_tick_index,
+                    upper_tick_index,
+                })
+        }
+        _ => strategy
+            .range_spec(
+                Clock::get()?.unix_timestamp,
+                ctx.accounts.vault_stats.last_rebalance_timestamp,
+            )
+            .unwrap_or(RangeSpec::Ticks {
+                lower_tick_index,
+                upper_tick_index,
+            }),
+    };
+    msg!("strategy: {:?}, range_spec: {:?}", strategy, range_spec);
+
+    // whirlpool only accepts ticks on the pool's tick_spacing
+    let (lower_tick_index, upper_tick_index) =
+        range_spec.to_tick_range(ctx.accounts.pool.sqrt_price, ctx.accounts.pool.tick_spacing)?;
+    msg!(
+        "lower_tick_index: {}, upper_tick_index: {}",
+        lower_tick_index,
+        upper_tick_index
+    );
+
+    // fail early instead of after the swap if the new range is already stale
+    if !position_in_range(tick_index_current, lower_tick_index, upper_tick_index) {
+        return Err(error!(BloomErrorCode::PositionOutOfRange));
+    }
+
+    check_tick_array(
+        &ctx.accounts.tick_array_lower.to_account_info(),
+        &ctx.accounts.pool,
+        pool_position.tick_lower_index,
+        BloomErrorCode::InvalidTickArrayLower,
+    )?;
+    check_tick_array(
+        &ctx.accounts.tick_array_upper.to_account_info(),
+        &ctx.accounts.pool,
+        pool_position.tick_upper_index,
+        BloomErrorCode::InvalidTickArrayUpper,
+    )?;
+
+    let token_a_vault_before = ctx.accounts.token_a_vault.amount;
+    let token_b_vault_before = ctx.accounts.token_b_vault.amount;
+
+    // decrease liquidity
+    let decrease_liquidity_accounts = DecreaseLiquidity {
+        whirlpool: ctx.accounts.pool.to_account_info(),
+        token_program: ctx.accounts.token_program.to_account_info(),
+        position_authority: ctx.accounts.vault_manager.to_account_info(),
+        position: ctx.accounts.pool_position.to_account_info(),
+        position_token_account: ctx.accounts.pool_position_token_account.to_account_info(),
+        token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
+        token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
+        token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
+        token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
+        tick_array_lower: ctx.accounts.tick_array_lower.to_account_info(),
+        tick_array_upper: ctx.accounts.tick_array_upper.to_account_info(),
+    };
+
+    decrease_liquidity(
+        CpiContext::new_with_signer(
+            ctx.accounts.whirlpool_program.to_account_info(),
+            decrease_liquidity_accounts,
+            &[&[
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("vault_manager").unwrap()],
+            ]],
+        ),
+        pool_position.liquidity,
+        0,
+        0,
+    )?;
+
+    // decrease_liquidity settles the fees owed, collect_fees pays out exactly this
+    pool_position.reload()?;
+    let fee_a = pool_position.fee_owed_a;
+    let fee_b = pool_position.fee_owed_b;
+
+    // collect fees
+    let collect_fees_accounts = CollectFees {
+        whirlpool: ctx.accounts.pool.to_account_info(),
+        position_authority: ctx.accounts.vault_manager.to_account_info(),
+        position: ctx.accounts.pool_position.to_account_info(),
+        position_token_account: ctx.accounts.pool_position_token_account.to_account_info(),
+        token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
+        token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
+        token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
+        token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
+        token_program: ctx.accounts.token_program.to_account_info(),
+    };
+
+    collect_fees(CpiContext::new_with_signer(
+        ctx.accounts.whirlpool_program.to_account_info(),
+        collect_fees_accounts,
+        &[&[
+            ctx.accounts.pool.key().as_ref(),
+            &[*ctx.bumps.get("vault_manager").unwrap()],
+        ]],
+    ))?;
+
+    emit!(FeesCollected {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        pool_position: ctx.accounts.pool_position.key(),
+        fee_a,
+        fee_b,
+    });
+
+    // close position
+    let close_position_accounts = ClosePosition {
+        position: ctx.accounts.pool_position.to_account_info(),
+        receiver: ctx.accounts.crank.to_account_info(),
+        position_mint: ctx.accounts.pool_position_mint.to_account_info(),
+        position_token_account: ctx.accounts.pool_position_token_account.to_account_info(),
+        position_authority: ctx.accounts.vault_manager.to_account_info(),
+        token_program: ctx.accounts.token_program.to_account_info(),
+    };
+
+    close_position(CpiContext::new_with_signer(
+        ctx.accounts.whirlpool_program.to_account_info(),
+        close_position_accounts,
+        &[&[
+            ctx.accounts.pool.key().as_ref(),
+            &[*ctx.bumps.get("vault_manager").unwrap()],
+        ]],
+    ))?;
+
+    ctx.accounts.token_a_vault.reload()?;
+    ctx.accounts.token_b_vault.reload()?;
+
+    msg!(
+        "after rebalance_withdraw\tvault_a: {}, vault_b: {}",
+        ctx.accounts.token_a_vault.amount,
+        ctx.accounts.token_b_vault.amount
+    );
+
+    emit!(RebalanceWithdrawn {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        old_tick_lower_index: pool_position.tick_lower_index,
+        old_tick_upper_index: pool_position.tick_upper_index,
+        new_tick_lower_index: lower_tick_index,
+        new_tick_upper_index: upper_tick_index,
+        token_a_amount: ctx.accounts.token_a_vault.amount - token_a_vault_before,
+        token_b_amount: ctx.accounts.token_b_vault.amount - token_b_vault_before,
+    });
+
+    // started here, rebalance_swap and rebalance_deposit fill in the rest
+    *ctx.accounts.rebalance_history.load_mut()?.pending_mut() = RebalanceEntry {
+        slot: Clock::get()?.slot,
+        old_tick_lower_index: pool_position.tick_lower_index,
+        old_tick_upper_index: pool_position.tick_upper_index,
+        new_tick_lower_index: lower_tick_index,
+        new_tick_upper_index: upper_tick_index,
+        sqrt_price_before_x64: ctx.accounts.pool.sqrt_price,
+        sqrt_price_after_x64: 0,
+        a_to_b: 0,
+        swap_amount_in: 0,
+        swap_amount_out: 0,
+        token_a_leftover: 0,
+        token_b_leftover: 0,
+    };
+
+    // liquidity sits idle in the vault until rebalance_deposit
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.update_time(Clock::get()?.unix_timestamp, false);
+    vault_stats.fees_earned_a += fee_a;
+    vault_stats.fees_earned_b += fee_b;
+
+    // remember the target range so the swap and deposit steps use the same ratio
+    let vault_manager = &mut ctx.accounts.vault_manager;
+    vault_manager.rebalance_state = RebalanceState::Withdrawn;
+    vault_manager.rebalance_lower_tick_index = lower_tick_index;
+    vault_manager.rebalance_upper_tick_index = upper_tick_index;
+    vault_manager.rebalance_fee_a = fee_a;
+    vault_manager.rebalance_fee_b = fee_b;
+
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use anchor_lang::prelude::*;
+
+use super::*;
+
+#[derive(Accounts)]
+pub struct RemoveFromAllowlist<'info> {
+    #[account(has_one = admin)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    // the user can still withdraw, only new deposits are blocked
+    #[account(mut, close = admin,
+        seeds = [b"allowlist", vault_manager.key().as_ref(), allowlist_entry.user.as_ref()], bump)]
+    pub allowlist_entry: Account<'info, AllowlistEntry>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+pub fn handler(_ctx: Context<RemoveFromAllowlist>) -> Result<()> {
+    Ok(())
+}
@@ -7,6 +7,8 @@ use whirlpools::program::Whirlpool as WhirlpoolProgram;
 use whirlpools::state::{TickArray, Whirlpool};
 
 use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::Withdrawn;
 use crate::math;
 
 #[derive(Accounts)]
@@ -19,9 +21,13 @@ pub struct RemoveLiquidity<'info> {
     has_one = token_a,
     has_one = token_b,
     has_one = token_a_pool_vault,
-    has_one = token_b_pool_vault)]
+    has_one = token_b_pool_vault,
+    constraint = !vault_manager.rebalance_state.in_progress() @ BloomErrorCode::RebalanceInProgress)]
     pub vault_manager: Box<Account<'info, VaultManager>>,
 
+    #[account(mut, seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
     pub token_a: Box<Account<'info, token::Mint>>,
 
     pub token_b: Box<Account<'info, token::Mint>>,
@@ -42,7 +48,7 @@ pub struct RemoveLiquidity<'info> {
     #[account(mut, seeds = [b"position", pool_position_mint.key().as_ref()], bump, seeds::program = whirlpool_program)]
     pub pool_position: UncheckedAccount<'info>,
 
-    #[account(seeds = [b"pool_position_mint", vault_manager.pool_position_mint_seed.as_bytes(), pool.key().as_ref()], bump)]
+    #[account(address = vault_manager.pool_position_mint)]
     pub pool_position_mint: Box<Account<'info, token::Mint>>,
 
     #[account(token::mint = pool_position_mint, token::authority = vault_manager)]
@@ -66,11 +72,56 @@ pub struct RemoveLiquidity<'info> {
     #[account(mut, associated_token::mint = pool_token, associated_token::authority = user)]
     pub user_pool_token_ata: Account<'info, token::TokenAccount>,
 
+    // created on the first withdraw of pool tokens received from another wallet
+    #[account(init_if_needed, payer = user, space = UserPosition::space(), seeds = [b"user_position", vault_manager.key().as_ref(), user.key().as_ref()], bump)]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
     pub token_program: Program<'info, token::Token>,
     pub whirlpool_program: Program<'info, WhirlpoolProgram>,
+    pub system_program: Program<'info, System>,
+}
+
+// how the size of a withdraw is specified, All redeems the user's whole pool token balance
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WithdrawMode {
+    All,
+    ExactLiquidity { liquidity: u128 },
+    ExactShares { pool_token_amount: u64 },
+}
+
+// returns the liquidity to remove and the pool tokens to burn for a withdraw
+pub fn calculate_withdraw(
+    withdraw_mode: WithdrawMode,
+    user_pool_token_amount: u64,
+    position_liquidity: u128,
+    pool_token_supply: u64,
+) -> Result<(u128, u64)> {
+    let pool_token_amount = match withdraw_mode {
+        WithdrawMode::All => user_pool_token_amount,
+        WithdrawMode::ExactShares { pool_token_amount } => pool_token_amount,
+        WithdrawMode::ExactLiquidity { liquidity } => {
+            let pool_token_amount = math::calculate_pool_tokens_for_liquidity(
+                liquidity,
+                position_liquidity,
+                pool_token_supply,
+                true,
+            )
+            .ok_or_else(|| error!(BloomErrorCode::PoolTokenAmountOverflow))?;
+            return Ok((liquidity, pool_token_amount));
+        }
+    };
+
+    let liquidity = math::calculate_liquidity_for_pool_tokens(
+        pool_token_amount,
+        pool_token_supply,
+        position_liquidity,
+        false,
+    );
+
+    Ok((liquidity, pool_token_amount))
 }
 
-pub fn handler(ctx: Context<RemoveLiquidity>) -> Result<()> {
+pub fn handler(ctx: Context<RemoveLiquidity>, withdraw_mode: WithdrawMode) -> Result<()> {
     // Have to do this hacky thing because of anchor-lang version mismatch
     let pool_position =
         &mut WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
@@ -79,20 +130,21 @@ pub fn handler(ctx: Context<RemoveLiquidity>) -> Result<()> {
     let tick_index_upper = pool_position.tick_upper_index;
     let tick_index_current = ctx.accounts.pool.tick_current_index;
 
-    // check that our position is within range of the pool
-    if tick_index_current < tick_index_lower || tick_index_current >= tick_index_upper {
-        return Ok(());
-    }
-
-    //let sqrt_price_current_x64 = ctx.accounts.pool.sqrt_price;
-    //let sqrt_price_upper_x64 = sqrt_price_from_tick_index(tick_index_upper);
+    check_tick_array(
+        &ctx.accounts.tick_array_lower.to_account_info(),
+        &ctx.accounts.pool,
+        tick_index_lower,
+        BloomErrorCode::InvalidTickArrayLower,
+    )?;
+    check_tick_array(
+        &ctx.accounts.tick_array_upper.to_account_info(),
+        &ctx.accounts.pool,
+        tick_index_upper,
+        BloomErrorCode::InvalidTickArrayUpper,
+    )?;
 
-    //let liquidity = get_liquidity_from_token_a(
-    //    token_a_amount_out as u128,
-    //    sqrt_price_current_x64,
-    //    sqrt_price_upper_x64,
-    //);
-    //msg!("liquidity: {}", liquidity,);
+    // withdrawals stay open out of range, a resting range order is never in range
+    let in_range = position_in_range(tick_index_current, tick_index_lower, tick_index_upper);
 
     let decrease_liquidity_accounts = DecreaseLiquidity {
         whirlpool: ctx.accounts.pool.to_account_info(),
@@ -108,12 +160,41 @@ pub fn handler(ctx: Context<RemoveLiquidity>) -> Result<()> {
         tick_array_upper: ctx.accounts.tick_array_upper.to_account_info(),
     };
 
-    let liquidity = math::calculate_remove_liquidity_amount(
+    let (liquidity, pool_token_amount) = calculate_withdraw(
+        withdraw_mode,
         ctx.accounts.user_pool_token_ata.amount,
-        ctx.accounts.pool_token.supply,
         pool_position.liquidity,
+        ctx.accounts.pool_token.supply,
+    )?;
+    msg!(
+        "withdraw_mode: {:?}, liquidity_to_remove: {}, pool_token_burn_amount: {}",
+        withdraw_mode,
+        liquidity,
+        pool_token_amount
     );
-    msg!("liquidity_to_remove: {}", liquidity);
+
+    if pool_token_amount > ctx.accounts.user_pool_token_ata.amount {
+        return Err(error!(BloomErrorCode::InsufficientPoolTokens));
+    }
+
+    // withdrawing before min_deposit_duration either fails or leaves a fee in the position
+    let now = Clock::get()?.unix_timestamp;
+    let untracked_pool_tokens = ctx
+        .accounts
+        .user_position
+        .untracked_pool_tokens(ctx.accounts.user_pool_token_ata.amount);
+    let early_exit_fee = ctx.accounts.vault_manager.early_exit_fee(
+        liquidity,
+        pool_token_amount,
+        untracked_pool_tokens,
+        now,
+        ctx.accounts.user_position.last_deposit_timestamp,
+    )?;
+    let liquidity = liquidity - early_exit_fee;
+    msg!("early_exit_fee: {}", early_exit_fee);
+
+    let token_a_before = ctx.accounts.user_token_a_ata.amount;
+    let token_b_before = ctx.accounts.user_token_b_ata.amount;
 
     decrease_liquidity(
         CpiContext::new_with_signer(
@@ -135,6 +216,9 @@ pub fn handler(ctx: Context<RemoveLiquidity>) -> Result<()> {
         pool_position.liquidity
     );
 
+    ctx.accounts.user_token_a_ata.reload()?;
+    ctx.accounts.user_token_b_ata.reload()?;
+
     // approve vault manager to burn the pool tokens
     let pool_token_burn_approve_accounts = token::Approve {
         to: ctx.accounts.user_pool_token_ata.to_account_info(),
@@ -147,7 +231,7 @@ pub fn handler(ctx: Context<RemoveLiquidity>) -> Result<()> {
             ctx.accounts.token_program.to_account_info(),
             pool_token_burn_approve_accounts,
         ),
-        ctx.accounts.user_pool_token_ata.amount,
+        pool_token_amount,
     )?;
 
     let pool_token_burn_accounts = token::Burn {
@@ -165,6 +249,40 @@ pub fn handler(ctx: Context<RemoveLiquidity>) -> Result<()> {
                 &[*ctx.bumps.get("vault_manager").unwrap()],
             ]],
         ),
-        ctx.accounts.user_pool_token_ata.amount,
-    )
+        pool_token_amount,
+    )?;
+
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.update_time(now, in_range);
+    vault_stats.update_share_price(
+        pool_position.liquidity,
+        tick_index_lower,
+        tick_index_upper,
+        ctx.accounts.pool.sqrt_price,
+        ctx.accounts.pool_token.supply - pool_token_amount,
+    );
+    vault_stats.last_withdraw_timestamp = now;
+
+    let token_a_amount = ctx.accounts.user_token_a_ata.amount - token_a_before;
+    let token_b_amount = ctx.accounts.user_token_b_ata.amount - token_b_before;
+
+    let user_position = &mut ctx.accounts.user_position;
+    user_position.set_owner_if_new(ctx.accounts.vault_manager.key(), ctx.accounts.user.key());
+    user_position.record_withdraw(
+        token_a_amount,
+        token_b_amount,
+        pool_token_amount,
+        untracked_pool_tokens,
+    );
+
+    emit!(Withdrawn {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        user: ctx.accounts.user.key(),
+        liquidity,
+        token_a_amount,
+        token_b_amount,
+        pool_token_amount,
+    });
+
+    Ok(())
 }
@@ -0,0 +1,371 @@
+use anchor_lang::prelude::*;
+use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
+use anchor_spl::token;
+use whirlpool::{
+    math::{MAX_SQRT_PRICE_X64, MIN_SQRT_PRICE_X64},
+    state::Position as WhirlpoolPosition,
+};
+use whirlpools::cpi::{
+    accounts::{DecreaseLiquidity, Swap},
+    {decrease_liquidity, swap},
+};
+use whirlpools::program::Whirlpool as WhirlpoolProgram;
+use whirlpools::state::{TickArray, Whirlpool};
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::Withdrawn;
+use crate::math;
+
+#[derive(Accounts)]
+pub struct RemoveLiquiditySingleSided<'info> {
+    #[account(seeds = [pool.key().as_ref()], bump,
+    has_one = pool_position,
+    has_one = pool_position_token_account,
+    has_one = pool,
+    has_one = pool_token,
+    has_one = token_a,
+    has_one = token_b,
+    has_one = token_a_vault,
+    has_one = token_b_vault,
+    has_one = token_a_pool_vault,
+    has_one = token_b_pool_vault,
+    constraint = !vault_manager.rebalance_state.in_progress() @ BloomErrorCode::RebalanceInProgress)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(mut, seeds = [b"vault_stats", vault_manager.key().as_ref()], bump)]
+    pub vault_stats: Box<Account<'info, VaultStats>>,
+
+    pub token_a: Box<Account<'info, token::Mint>>,
+
+    pub token_b: Box<Account<'info, token::Mint>>,
+
+    #[account(mut, seeds = [b"pool_token", vault_manager.key().as_ref()], bump)]
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_a.key().as_ref()], bump, token::mint = token_a, token::authority = vault_manager)]
+    pub token_a_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, seeds = [vault_manager.key().as_ref(), token_b.key().as_ref()], bump, token::mint = token_b, token::authority = vault_manager)]
+    pub token_b_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    #[account(mut, token::mint = token_a)]
+    pub token_a_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, token::mint = token_b)]
+    pub token_b_pool_vault: Box<Account<'info, token::TokenAccount>>,
+
+    /// CHECK: deserialized inside instruction
+    #[account(mut, seeds = [b"position", pool_position_mint.key().as_ref()], bump, seeds::program = whirlpool_program)]
+    pub pool_position: UncheckedAccount<'info>,
+
+    #[account(address = vault_manager.pool_position_mint)]
+    pub pool_position_mint: Box<Account<'info, token::Mint>>,
+
+    #[account(token::mint = pool_position_mint, token::authority = vault_manager)]
+    pub pool_position_token_account: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut)]
+    pub tick_array_lower: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_upper: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_current: AccountLoader<'info, TickArray>,
+
+    // the next two tick arrays in the direction of the swap
+    #[account(mut)]
+    pub tick_array_1: AccountLoader<'info, TickArray>,
+
+    #[account(mut)]
+    pub tick_array_2: AccountLoader<'info, TickArray>,
+
+    /// CHECK: Must be provided for swapping but not currently used by Whirlpool Program
+    #[account(seeds = [b"oracle", pool.key().as_ref()], bump, seeds::program = whirlpool_program)]
+    pub oracle: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    // the mint of this account decides which token the user is paid in
+    #[account(mut, token::authority = user,
+        constraint = user_token_out_ata.mint == token_a.key() || user_token_out_ata.mint == token_b.key() @ BloomErrorCode::InvalidOutputMint)]
+    pub user_token_out_ata: Box<Account<'info, token::TokenAccount>>,
+
+    #[account(mut, associated_token::mint = pool_token, associated_token::authority = user)]
+    pub user_pool_token_ata: Box<Account<'info, token::TokenAccount>>,
+
+    // created on the first withdraw of pool tokens received from another wallet
+    #[account(init_if_needed, payer = user, space = UserPosition::space(), seeds = [b"user_position", vault_manager.key().as_ref(), user.key().as_ref()], bump)]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    pub token_program: Program<'info, token::Token>,
+    pub whirlpool_program: Program<'info, WhirlpoolProgram>,
+    pub system_program: Program<'info, System>,
+}
+
+// withdraw into the vault, swap the unwanted side through the pool and pay out a single token
+pub fn handler(ctx: Context<RemoveLiquiditySingleSided>, min_amount_out: u64) -> Result<()> {
+    // Have to do this hacky thing because of anchor-lang version mismatch
+    let pool_position =
+        &mut WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
+
+    check_tick_array(
+        &ctx.accounts.tick_array_lower.to_account_info(),
+        &ctx.accounts.pool,
+        pool_position.tick_lower_index,
+        BloomErrorCode::InvalidTickArrayLower,
+    )?;
+    check_tick_array(
+        &ctx.accounts.tick_array_upper.to_account_info(),
+        &ctx.accounts.pool,
+        pool_position.tick_upper_index,
+        BloomErrorCode::InvalidTickArrayUpper,
+    )?;
+
+    let in_range = position_in_range(
+        ctx.accounts.pool.tick_current_index,
+        pool_position.tick_lower_index,
+        pool_position.tick_upper_index,
+    );
+    let output_is_token_a = ctx.accounts.user_token_out_ata.mint == ctx.accounts.token_a.key();
+
+    check_swap_tick_arrays(
+        [
+            &ctx.accounts.tick_array_current.to_account_info(),
+            &ctx.accounts.tick_array_1.to_account_info(),
+            &ctx.accounts.tick_array_2.to_account_info(),
+        ],
+        &ctx.accounts.pool,
+        !output_is_token_a,
+    )?;
+
+    let pool_token_amount = ctx.accounts.user_pool_token_ata.amount;
+
+    // idle vault balances belong to the vault, only the withdrawn tokens are paid out
+    let token_a_vault_before = ctx.accounts.token_a_vault.amount;
+    let token_b_vault_before = ctx.accounts.token_b_vault.amount;
+
+    let liquidity = math::calculate_liquidity_for_pool_tokens(
+        pool_token_amount,
+        ctx.accounts.pool_token.supply,
+        pool_position.liquidity,
+        false,
+    );
+
+    // withdrawing before min_deposit_duration either fails or leaves a fee in the position
+    let now = Clock::get()?.unix_timestamp;
+    let untracked_pool_tokens = ctx
+        .accounts
+        .user_position
+        .untracked_pool_tokens(ctx.accounts.user_pool_token_ata.amount);
+    let early_exit_fee = ctx.accounts.vault_manager.early_exit_fee(
+        liquidity,
+        pool_token_amount,
+        untracked_pool_tokens,
+        now,
+        ctx.accounts.user_position.last_deposit_timestamp,
+    )?;
+    let liquidity = liquidity - early_exit_fee;
+    msg!("early_exit_fee: {}", early_exit_fee);
+    msg!("liquidity_to_remove: {}", liquidity);
+
+    let decrease_liquidity_accounts = DecreaseLiquidity {
+        whirlpool: ctx.accounts.pool.to_account_info(),
+        token_program: ctx.accounts.token_program.to_account_info(),
+        position_authority: ctx.accounts.vault_manager.to_account_info(),
+        position: pool_position.to_account_info(),
+        position_token_account: ctx.accounts.pool_position_token_account.to_account_info(),
+        token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
+        token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
+        token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
+        token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
+        tick_array_lower: ctx.accounts.tick_array_lower.to_account_info(),
+        tick_array_upper: ctx.accounts.tick_array_upper.to_account_info(),
+    };
+
+    decrease_liquidity(
+        CpiContext::new_with_signer(
+            ctx.accounts.whirlpool_program.to_account_info(),
+            decrease_liquidity_accounts,
+            &[&[
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("vault_manager").unwrap()],
+            ]],
+        ),
+        liquidity,
+        0,
+        0,
+    )?;
+
+    ctx.accounts.token_a_vault.reload()?;
+    ctx.accounts.token_b_vault.reload()?;
+
+    let token_a_withdrawn = ctx.accounts.token_a_vault.amount - token_a_vault_before;
+    let token_b_withdrawn = ctx.accounts.token_b_vault.amount - token_b_vault_before;
+    msg!(
+        "token_a_withdrawn: {}, token_b_withdrawn: {}",
+        token_a_withdrawn,
+        token_b_withdrawn
+    );
+
+    // swap the side the user does not want into the side they do
+    let (a_to_b, swap_amount) = if output_is_token_a {
+        (false, token_b_withdrawn)
+    } else {
+        (true, token_a_withdrawn)
+    };
+
+    if swap_amount > 0 {
+        let sqrt_price_limit = if a_to_b {
+            MIN_SQRT_PRICE_X64
+        } else {
+            MAX_SQRT_PRICE_X64
+        };
+
+        let swap_accounts = Swap {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            token_authority: ctx.accounts.vault_manager.to_account_info(),
+            whirlpool: ctx.accounts.pool.to_account_info(),
+            token_owner_account_a: ctx.accounts.token_a_vault.to_account_info(),
+            token_owner_account_b: ctx.accounts.token_b_vault.to_account_info(),
+            token_vault_a: ctx.accounts.token_a_pool_vault.to_account_info(),
+            token_vault_b: ctx.accounts.token_b_pool_vault.to_account_info(),
+            tick_array0: ctx.accounts.tick_array_current.to_account_info(),
+            tick_array1: ctx.accounts.tick_array_1.to_account_info(),
+            tick_array2: ctx.accounts.tick_array_2.to_account_info(),
+            oracle: ctx.accounts.oracle.to_account_info(),
+        };
+
+        swap(
+            CpiContext::new_with_signer(
+                ctx.accounts.whirlpool_program.to_account_info(),
+                swap_accounts,
+                &[&[
+                    ctx.accounts.pool.key().as_ref(),
+                    &[*ctx.bumps.get("vault_manager").unwrap()],
+                ]],
+            ),
+            swap_amount,
+            0,
+            sqrt_price_limit,
+            true,
+            a_to_b,
+        )?;
+
+        ctx.accounts.token_a_vault.reload()?;
+        ctx.accounts.token_b_vault.reload()?;
+    }
+
+    let (amount_out, token_vault) = if output_is_token_a {
+        (
+            ctx.accounts.token_a_vault.amount - token_a_vault_before,
+            ctx.accounts.token_a_vault.to_account_info(),
+        )
+    } else {
+        (
+            ctx.accounts.token_b_vault.amount - token_b_vault_before,
+            ctx.accounts.token_b_vault.to_account_info(),
+        )
+    };
+    msg!("amount_out: {}", amount_out);
+
+    if amount_out < min_amount_out {
+        return Err(error!(BloomErrorCode::SlippageExceeded));
+    }
+
+    let transfer_out_accounts = token::Transfer {
+        from: token_vault,
+        to: ctx.accounts.user_token_out_ata.to_account_info(),
+        authority: ctx.accounts.vault_manager.to_account_info(),
+    };
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            transfer_out_accounts,
+            &[&[
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("vault_manager").unwrap()],
+            ]],
+        ),
+        amount_out,
+    )?;
+
+    // approve vault manager to burn the pool tokens
+    let pool_token_burn_approve_accounts = token::Approve {
+        to: ctx.accounts.user_pool_token_ata.to_account_info(),
+        delegate: ctx.accounts.vault_manager.to_account_info(),
+        authority: ctx.accounts.user.to_account_info(),
+    };
+
+    token::approve(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            pool_token_burn_approve_accounts,
+        ),
+        pool_token_amount,
+    )?;
+
+    let pool_token_burn_accounts = token::Burn {
+        mint: ctx.accounts.pool_token.to_account_info(),
+        from: ctx.accounts.user_pool_token_ata.to_account_info(),
+        authority: ctx.accounts.vault_manager.to_account_info(),
+    };
+
+    token::burn(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            pool_token_burn_accounts,
+            &[&[
+                ctx.accounts.pool.key().as_ref(),
+                &[*ctx.bumps.get("vault_manager").unwrap()],
+            ]],
+        ),
+        pool_token_amount,
+    )?;
+
+    let (token_a_amount, token_b_amount) = if output_is_token_a {
+        (amount_out, 0)
+    } else {
+        (0, amount_out)
+    };
+
+    // the swap moved the price the remaining shares are valued at
+    ctx.accounts.pool.reload()?;
+
+    let vault_stats = &mut ctx.accounts.vault_stats;
+    vault_stats.update_time(now, in_range);
+    vault_stats.update_share_price(
+        pool_position.liquidity - liquidity,
+        pool_position.tick_lower_index,
+        pool_position.tick_upper_index,
+        ctx.accounts.pool.sqrt_price,
+        ctx.accounts.pool_token.supply - pool_token_amount,
+    );
+    vault_stats.last_withdraw_timestamp = now;
+
+    let user_position = &mut ctx.accounts.user_position;
+    user_position.set_owner_if_new(ctx.accounts.vault_manager.key(), ctx.accounts.user.key());
+    user_position.record_withdraw(
+        token_a_amount,
+        token_b_amount,
+        pool_token_amount,
+        untracked_pool_tokens,
+    );
+
+    emit!(Withdrawn {
+        vault_manager: ctx.accounts.vault_manager.key(),
+        user: ctx.accounts.user.key(),
+        liquidity,
+        token_a_amount,
+        token_b_amount,
+        pool_token_amount,
+    });
+
+    Ok(())
+}
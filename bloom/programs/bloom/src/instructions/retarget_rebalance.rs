@@ -0,0 +1,51 @@
+use anchor_lang::prelude::*;
+use whirlpools::state::Whirlpool;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::events::RebalanceRetargeted;
+
+#[derive(Accounts)]
+pub struct RetargetRebalance<'info> {
+    #[account(mut,
+        has_one = admin,
+        has_one = pool,
+        constraint = vault_manager.rebalance_state.in_progress() @ BloomErrorCode::InvalidRebalanceState)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    pub admin: Signer<'info>,
+}
+
+// the price can leave the target range between rebalance_withdraw and rebalance_deposit, which
+// would leave the vault locked in a rebalance. pick a new range and redo the swap for it
+pub fn handler(ctx: Context<RetargetRebalance>, range_spec: RangeSpec) -> Result<()> {
+    let (lower_tick_index, upper_tick_index) =
+        range_spec.to_tick_range(ctx.accounts.pool.sqrt_price, ctx.accounts.pool.tick_spacing)?;
+
+    if !position_in_range(
+        ctx.accounts.pool.tick_current_index,
+        lower_tick_index,
+        upper_tick_index,
+    ) {
+        return Err(error!(BloomErrorCode::PositionOutOfRange));
+    }
+
+    let vault_manager = &mut ctx.accounts.vault_manager;
+
+    emit!(RebalanceRetargeted {
+        vault_manager: vault_manager.key(),
+        old_tick_lower_index: vault_manager.rebalance_lower_tick_index,
+        old_tick_upper_index: vault_manager.rebalance_upper_tick_index,
+        new_tick_lower_index: lower_tick_index,
+        new_tick_upper_index: upper_tick_index,
+        range_spec,
+    });
+
+    vault_manager.rebalance_state = RebalanceState::Withdrawn;
+    vault_manager.rebalance_lower_tick_index = lower_tick_index;
+    vault_manager.rebalance_upper_tick_index = upper_tick_index;
+
+    Ok(())
+}
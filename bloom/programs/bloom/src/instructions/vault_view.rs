@@ -0,0 +1,77 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program::set_return_data;
+use anchor_lang_for_whirlpool::accounts::account::Account as WhirlpoolAccount;
+use anchor_spl::token;
+use whirlpool::state::Position as WhirlpoolPosition;
+use whirlpools::state::Whirlpool;
+
+use super::*;
+use crate::errors::BloomErrorCode;
+use crate::math;
+
+// read-only accounts for the tokenized vault views, assets are liquidity in the vault's position
+// and shares are pool tokens
+#[derive(Accounts)]
+pub struct VaultView<'info> {
+    #[account(seeds = [pool.key().as_ref()], bump,
+        has_one = pool_position,
+        has_one = pool,
+        has_one = pool_token)]
+    pub vault_manager: Box<Account<'info, VaultManager>>,
+
+    #[account(seeds = [b"pool_token", vault_manager.key().as_ref()], bump)]
+    pub pool_token: Box<Account<'info, token::Mint>>,
+
+    pub pool: Box<Account<'info, Whirlpool>>,
+
+    /// CHECK: deserialized inside instruction
+    pub pool_position: UncheckedAccount<'info>,
+}
+
+fn position_liquidity(ctx: &Context<VaultView>) -> Result<u128> {
+    // Have to do this hacky thing because of anchor-lang version mismatch
+    let pool_position =
+        WhirlpoolAccount::<'_, WhirlpoolPosition>::try_from(&ctx.accounts.pool_position)?;
+
+    Ok(pool_position.liquidity)
+}
+
+pub fn total_assets_handler(ctx: Context<VaultView>) -> Result<()> {
+    let total_assets = position_liquidity(&ctx)?;
+    msg!("total_assets: {}", total_assets);
+
+    set_return_data(&total_assets.try_to_vec()?);
+
+    Ok(())
+}
+
+// rounds down like deposit
+pub fn convert_to_shares_handler(ctx: Context<VaultView>, assets: u128) -> Result<()> {
+    let shares = math::calculate_pool_tokens_for_liquidity(
+        assets,
+        position_liquidity(&ctx)?,
+        ctx.accounts.pool_token.supply,
+        false,
+    )
+    .ok_or_else(|| error!(BloomErrorCode::PoolTokenAmountOverflow))?;
+    msg!("assets: {}, shares: {}", assets, shares);
+
+    set_return_data(&shares.try_to_vec()?);
+
+    Ok(())
+}
+
+// rounds down like redeem
+pub fn convert_to_assets_handler(ctx: Context<VaultView>, shares: u64) -> Result<()> {
+    let assets = math::calculate_liquidity_for_pool_tokens(
+        shares,
+        ctx.accounts.pool_token.supply,
+        position_liquidity(&ctx)?,
+        false,
+    );
+    msg!("shares: {}, assets: {}", shares, assets);
+
+    set_return_data(&assets.try_to_vec()?);
+
+    Ok(())
+}
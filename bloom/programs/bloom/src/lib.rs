@@ -3,7 +3,9 @@ use anchor_lang::prelude::*;
 declare_id!("9ryxeAa6TDqRm8maHYyAi8w6X8KxbY8biXsi279vGJJN");
 
 pub mod errors;
+pub mod events;
 pub mod math;
+pub mod strategy;
 
 pub mod instructions;
 use instructions::*;
@@ -14,37 +16,213 @@ pub mod bloom {
 
     pub fn initialize_vault(
         ctx: Context<InitializeVault>,
-        pool_position_mint_seed: String,
         lower_tick_index: i32,
         upper_tick_index: i32,
     ) -> Result<()> {
         instructions::initialize_vault::handler(
             ctx,
-            pool_position_mint_seed,
-            lower_tick_index,
-            upper_tick_index,
+            RangeSpec::Ticks {
+                lower_tick_index,
+                upper_tick_index,
+            },
+            VaultMode::Liquidity,
         )
     }
 
-    pub fn add_liquidity(ctx: Context<AddLiquidity>, token_a_amount_in: u64) -> Result<()> {
-        instructions::add_liquidity::handler(ctx, token_a_amount_in)
+    pub fn initialize_vault_with_range(
+        ctx: Context<InitializeVault>,
+        range_spec: RangeSpec,
+    ) -> Result<()> {
+        instructions::initialize_vault::handler(ctx, range_spec, VaultMode::Liquidity)
+    }
+
+    pub fn initialize_range_order_vault(
+        ctx: Context<InitializeVault>,
+        range_spec: RangeSpec,
+        sell_a: bool,
+    ) -> Result<()> {
+        instructions::initialize_vault::handler(ctx, range_spec, VaultMode::RangeOrder { sell_a })
+    }
+
+    pub fn add_liquidity(ctx: Context<AddLiquidity>, deposit_mode: DepositMode) -> Result<()> {
+        instructions::add_liquidity::handler(ctx, deposit_mode)
+    }
+
+    pub fn add_liquidity_single_sided(
+        ctx: Context<AddLiquiditySingleSided>,
+        amount_in: u64,
+        input_is_token_a: bool,
+        min_pool_token_amount: u64,
+    ) -> Result<()> {
+        instructions::add_liquidity_single_sided::handler(
+            ctx,
+            amount_in,
+            input_is_token_a,
+            min_pool_token_amount,
+        )
+    }
+
+    pub fn remove_liquidity(
+        ctx: Context<RemoveLiquidity>,
+        withdraw_mode: WithdrawMode,
+    ) -> Result<()> {
+        instructions::remove_liquidity::handler(ctx, withdraw_mode)
+    }
+
+    // tokenized vault interface, assets are liquidity in the vault's position and shares are
+    // pool tokens. rounding always favours the vault
+    pub fn deposit(
+        ctx: Context<AddLiquidity>,
+        assets: u128,
+        token_max_a: u64,
+        token_max_b: u64,
+    ) -> Result<()> {
+        instructions::add_liquidity::handler(
+            ctx,
+            DepositMode::ExactLiquidity {
+                liquidity: assets,
+                token_max_a,
+                token_max_b,
+            },
+        )
+    }
+
+    pub fn mint(
+        ctx: Context<AddLiquidity>,
+        shares: u64,
+        token_max_a: u64,
+        token_max_b: u64,
+    ) -> Result<()> {
+        instructions::add_liquidity::handler(
+            ctx,
+            DepositMode::ExactShares {
+                pool_token_amount: shares,
+                token_max_a,
+                token_max_b,
+            },
+        )
+    }
+
+    pub fn withdraw(ctx: Context<RemoveLiquidity>, assets: u128) -> Result<()> {
+        instructions::remove_liquidity::handler(
+            ctx,
+            WithdrawMode::ExactLiquidity { liquidity: assets },
+        )
+    }
+
+    pub fn redeem(ctx: Context<RemoveLiquidity>, shares: u64) -> Result<()> {
+        instructions::remove_liquidity::handler(
+            ctx,
+            WithdrawMode::ExactShares {
+                pool_token_amount: shares,
+            },
+        )
+    }
+
+    pub fn queue_config_change(ctx: Context<QueueConfigChange>, config: VaultConfig) -> Result<()> {
+        instructions::queue_config_change::handler(ctx, config)
+    }
+
+    pub fn execute_config_change(ctx: Context<ExecuteConfigChange>) -> Result<()> {
+        instructions::execute_config_change::handler(ctx)
+    }
+
+    pub fn cancel_config_change(ctx: Context<CancelConfigChange>) -> Result<()> {
+        instructions::cancel_config_change::handler(ctx)
     }
 
-    pub fn remove_liquidity(ctx: Context<RemoveLiquidity>) -> Result<()> {
-        instructions::remove_liquidity::handler(ctx)
+    pub fn migrate_vault(ctx: Context<MigrateVault>) -> Result<()> {
+        instructions::migrate_vault::handler(ctx)
+    }
+
+    pub fn add_to_allowlist(ctx: Context<AddToAllowlist>, user: Pubkey) -> Result<()> {
+        instructions::add_to_allowlist::handler(ctx, user)
+    }
+
+    pub fn remove_from_allowlist(ctx: Context<RemoveFromAllowlist>) -> Result<()> {
+        instructions::remove_from_allowlist::handler(ctx)
+    }
+
+    pub fn join_allowlist(ctx: Context<JoinAllowlist>, proof: Vec<[u8; 32]>) -> Result<()> {
+        instructions::join_allowlist::handler(ctx, proof)
+    }
+
+    pub fn total_assets(ctx: Context<VaultView>) -> Result<()> {
+        instructions::vault_view::total_assets_handler(ctx)
+    }
+
+    pub fn convert_to_shares(ctx: Context<VaultView>, assets: u128) -> Result<()> {
+        instructions::vault_view::convert_to_shares_handler(ctx, assets)
+    }
+
+    pub fn convert_to_assets(ctx: Context<VaultView>, shares: u64) -> Result<()> {
+        instructions::vault_view::convert_to_assets_handler(ctx, shares)
+    }
+
+    pub fn remove_liquidity_single_sided(
+        ctx: Context<RemoveLiquiditySingleSided>,
+        min_amount_out: u64,
+    ) -> Result<()> {
+        instructions::remove_liquidity_single_sided::handler(ctx, min_amount_out)
+    }
+
+    pub fn preview_deposit(ctx: Context<PreviewDeposit>, deposit_mode: DepositMode) -> Result<()> {
+        instructions::preview_deposit::handler(ctx, deposit_mode)
+    }
+
+    pub fn preview_withdraw(ctx: Context<PreviewWithdraw>, pool_token_amount: u64) -> Result<()> {
+        instructions::preview_withdraw::handler(ctx, pool_token_amount)
+    }
+
+    pub fn preview_rebalance(
+        ctx: Context<PreviewRebalance>,
+        lower_tick_index: i32,
+        upper_tick_index: i32,
+    ) -> Result<()> {
+        instructions::preview_rebalance::handler(ctx, lower_tick_index, upper_tick_index)
     }
 
     pub fn rebalance_positions(
         ctx: Context<RebalancePositions>,
-        new_pool_position_mint_seed: String,
         lower_tick_index: i32,
         upper_tick_index: i32,
     ) -> Result<()> {
         instructions::rebalance_positions::handler(
             ctx,
-            new_pool_position_mint_seed,
-            lower_tick_index,
-            upper_tick_index,
+            RangeSpec::Ticks {
+                lower_tick_index,
+                upper_tick_index,
+            },
         )
     }
+
+    pub fn rebalance_positions_with_range(
+        ctx: Context<RebalancePositions>,
+        range_spec: RangeSpec,
+    ) -> Result<()> {
+        instructions::rebalance_positions::handler(ctx, range_spec)
+    }
+
+    pub fn rebalance_withdraw(
+        ctx: Context<RebalanceWithdraw>,
+        lower_tick_index: i32,
+        upper_tick_index: i32,
+    ) -> Result<()> {
+        instructions::rebalance_withdraw::handler(ctx, lower_tick_index, upper_tick_index)
+    }
+
+    pub fn rebalance_swap(ctx: Context<RebalanceSwap>) -> Result<()> {
+        instructions::rebalance_swap::handler(ctx)
+    }
+
+    pub fn rebalance_deposit(ctx: Context<RebalanceDeposit>) -> Result<()> {
+        instructions::rebalance_deposit::handler(ctx)
+    }
+
+    pub fn retarget_rebalance(
+        ctx: Context<RetargetRebalance>,
+        range_spec: RangeSpec,
+    ) -> Result<()> {
+        instructions::retarget_rebalance::handler(ctx, range_spec)
+    }
 }
@@ -1,10 +1,13 @@
-use anchor_lang::prelude::msg;
+use anchor_lang::solana_program::keccak::hashv;
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::MathematicalOps;
 use rust_decimal::{prelude::FromPrimitive, Decimal};
 use std::ops::{Div, Mul};
 
-use whirlpool::math::{mul_u256, U256Muldiv};
+use whirlpool::math::{
+    mul_u256, sqrt_price_from_tick_index, tick_index_from_sqrt_price, U256Muldiv,
+    MAX_SQRT_PRICE_X64, MIN_SQRT_PRICE_X64,
+};
 
 // convert square root price to decimal
 pub fn sqrt_price_x64_to_price(sqrt_price: u128, decimals_a: u8, decimals_b: u8) -> f64 {
@@ -95,6 +98,74 @@ pub fn calculate_deposit_ratio(
     (a_percentage.mul(100.0), b_percentage.mul(100.0))
 }
 
+// returns the swap direction (a_to_b) and max amount in needed to move the vault balances
+// towards the deposit ratio of a position
+pub fn calculate_rebalance_swap(
+    lower_price: f64,
+    current_price: f64,
+    upper_price: f64,
+    token_a_amount: u64,
+    token_b_amount: u64,
+    decimals_a: u8,
+    decimals_b: u8,
+) -> (bool, u64) {
+    let (token_a_percentage, token_b_percentage) =
+        calculate_deposit_ratio(lower_price, current_price, upper_price);
+
+    let token_a_amount = amount_to_ui_amount(token_a_amount, decimals_a);
+    let token_b_amount = amount_to_ui_amount(token_b_amount, decimals_b);
+
+    let token_b_leftover_amount = calculate_token_b_leftover(
+        token_a_percentage,
+        token_b_percentage,
+        token_a_amount,
+        token_b_amount,
+        current_price,
+    );
+    if token_b_leftover_amount > 0.0 {
+        let token_b_max_out = ui_amount_to_amount(
+            calculate_token_b_swap_amount(token_b_percentage, token_b_leftover_amount),
+            decimals_b,
+        );
+        return (false, token_b_max_out);
+    }
+
+    let token_a_leftover_amount = calculate_token_a_leftover(
+        token_a_percentage,
+        token_b_percentage,
+        token_a_amount,
+        token_b_amount,
+        current_price,
+    );
+    let token_a_max_out = ui_amount_to_amount(
+        calculate_token_a_swap_amount(token_a_percentage, token_a_leftover_amount),
+        decimals_a,
+    );
+
+    (true, token_a_max_out)
+}
+
+// returns how much of a single sided deposit to swap so the remainder matches the deposit ratio
+pub fn calculate_single_sided_swap_amount(
+    amount_in: u64,
+    input_is_token_a: bool,
+    lower_price: f64,
+    current_price: f64,
+    upper_price: f64,
+) -> u64 {
+    let (token_a_percentage, token_b_percentage) =
+        calculate_deposit_ratio(lower_price, current_price, upper_price);
+
+    // deposit ratio is by value, so swap the share of the other token
+    let swap_percentage = if input_is_token_a {
+        token_b_percentage
+    } else {
+        token_a_percentage
+    };
+
+    swap_percentage.mul(0.01).mul(amount_in as f64) as u64
+}
+
 // https://github.com/everlastingsong/solsandbox/blob/0fc97337c8da8d8315df575ce526405e08ddf0dd/orca/whirlpool/rust_cpi/cpi_whirlpool_increase_liquidity/programs/cpi_whirlpool_increase_liquidity/src/lib.rs#L139
 pub fn get_liquidity_from_token_a(
     amount: u128,
@@ -120,53 +191,554 @@ pub fn get_liquidity_from_token_a(
     liquidity
 }
 
-pub fn calculate_pool_token_mint_to_amount(
-    liquidity_to_deposit: u128,
-    position_liquidity_before_deposit: u128,
+pub fn get_liquidity_from_token_b(
+    amount: u128,
+    sqrt_price_lower_x64: u128,
+    sqrt_price_upper_x64: u128,
+) -> u128 {
+    // Δb = liquidity * (sqrt_price_upper - sqrt_price_lower)
+    // liquidity = Δb / (sqrt_price_upper - sqrt_price_lower)
+    assert!(sqrt_price_lower_x64 < sqrt_price_upper_x64);
+    let sqrt_price_diff = sqrt_price_upper_x64 - sqrt_price_lower_x64;
+
+    let numerator = U256Muldiv::new(0, amount).shift_word_left(); // x64
+    let denominator = U256Muldiv::new(0, sqrt_price_diff); // x64
+
+    let (quotient, _remainder) = numerator.div(denominator, false);
+
+    quotient.try_into_u128().unwrap()
+}
+
+// liquidity supported by both token amounts, the binding side decides
+pub fn get_liquidity_for_amounts(
+    amount_a: u128,
+    amount_b: u128,
+    sqrt_price_lower_x64: u128,
+    sqrt_price_current_x64: u128,
+    sqrt_price_upper_x64: u128,
+) -> u128 {
+    // below the range the position is entirely token a
+    if sqrt_price_current_x64 <= sqrt_price_lower_x64 {
+        return get_liquidity_from_token_a(amount_a, sqrt_price_lower_x64, sqrt_price_upper_x64);
+    }
+
+    // above the range the position is entirely token b
+    if sqrt_price_current_x64 >= sqrt_price_upper_x64 {
+        return get_liquidity_from_token_b(amount_b, sqrt_price_lower_x64, sqrt_price_upper_x64);
+    }
+
+    let liquidity_a =
+        get_liquidity_from_token_a(amount_a, sqrt_price_current_x64, sqrt_price_upper_x64);
+    let liquidity_b =
+        get_liquidity_from_token_b(amount_b, sqrt_price_lower_x64, sqrt_price_current_x64);
+
+    liquidity_a.min(liquidity_b)
+}
+
+// liquidity scaled down so neither token delta exceeds the balance it is paid from. the deltas
+// round up, so liquidity from get_liquidity_for_amounts can still ask for a unit too much
+pub fn scale_liquidity_to_balances(
+    liquidity: u128,
+    token_a_needed: u64,
+    token_b_needed: u64,
+    token_a_balance: u64,
+    token_b_balance: u64,
+) -> u128 {
+    let scale = |needed: u64, balance: u64| -> u128 {
+        if needed <= balance {
+            return liquidity;
+        }
+
+        // balance < needed so the quotient is below liquidity and fits a u128
+        let (quotient, _remainder) =
+            mul_u256(liquidity, balance as u128).div(U256Muldiv::new(0, needed as u128), false);
+        quotient.try_into_u128().unwrap()
+    };
+
+    scale(token_a_needed, token_a_balance).min(scale(token_b_needed, token_b_balance))
+}
+
+// liquidity backing an amount of pool tokens. round up when the user pays in liquidity (mint,
+// withdraw) and down when the vault pays out (redeem) so rounding never dilutes existing holders
+pub fn calculate_liquidity_for_pool_tokens(
+    pool_token_amount: u64,
     pool_token_supply: u64,
-) -> u64 {
-    // no tokens are minted, so mint the liquidity amount
+    position_liquidity: u128,
+    round_up: bool,
+) -> u128 {
+    // no tokens are minted, pool tokens and liquidity are 1:1
     if pool_token_supply == 0 {
-        msg!("first deposit into vault");
-        return liquidity_to_deposit as u64;
+        return pool_token_amount as u128;
+    }
+
+    let numerator = mul_u256(position_liquidity, pool_token_amount as u128);
+    let denominator = U256Muldiv::new(0, pool_token_supply as u128);
+
+    let (quotient, _remainder) = numerator.div(denominator, round_up);
+
+    quotient.try_into_u128().unwrap()
+}
+
+// pool tokens representing an amount of liquidity. round down when minting for a deposit and
+// up when burning for a withdraw, same reasoning as calculate_liquidity_for_pool_tokens.
+// None if the amount does not fit in a u64
+pub fn calculate_pool_tokens_for_liquidity(
+    liquidity: u128,
+    position_liquidity: u128,
+    pool_token_supply: u64,
+    round_up: bool,
+) -> Option<u64> {
+    // no tokens are minted, pool tokens and liquidity are 1:1
+    if pool_token_supply == 0 || position_liquidity == 0 {
+        return u64::try_from(liquidity).ok();
+    }
+
+    let numerator = mul_u256(liquidity, pool_token_supply as u128);
+    let denominator = U256Muldiv::new(0, position_liquidity);
+
+    let (quotient, _remainder) = numerator.div(denominator, round_up);
+
+    u64::try_from(quotient.try_into_u128().ok()?).ok()
+}
+
+// token amounts held by liquidity at the current price, rounded down
+pub fn get_amounts_for_liquidity(
+    liquidity: u128,
+    sqrt_price_lower_x64: u128,
+    sqrt_price_current_x64: u128,
+    sqrt_price_upper_x64: u128,
+) -> (u64, u64) {
+    let sqrt_price_current_x64 =
+        sqrt_price_current_x64.clamp(sqrt_price_lower_x64, sqrt_price_upper_x64);
+
+    // Δa = liquidity * (sqrt_price_upper - sqrt_price_current) / (sqrt_price_current * sqrt_price_upper)
+    let amount_a = if sqrt_price_current_x64 < sqrt_price_upper_x64 {
+        let (step, _remainder) = mul_u256(liquidity, sqrt_price_upper_x64 - sqrt_price_current_x64)
+            .div(U256Muldiv::new(0, sqrt_price_upper_x64), false);
+        let (quotient, _remainder) = step
+            .shift_word_left()
+            .div(U256Muldiv::new(0, sqrt_price_current_x64), false);
+        quotient.try_into_u128().unwrap_or(u128::MAX)
+    } else {
+        0
     };
 
-    // get percentage of deposit compared to total position amount (current deposit included)
-    let liquidity_deposit_percentage: f64 =
-        liquidity_to_deposit as f64 / position_liquidity_before_deposit as f64;
+    // Δb = liquidity * (sqrt_price_current - sqrt_price_lower)
+    let amount_b = mul_u256(liquidity, sqrt_price_current_x64 - sqrt_price_lower_x64)
+        .shift_word_right()
+        .try_into_u128()
+        .unwrap_or(u128::MAX);
 
-    msg!(
-        "liquidity_deposit_percentage: {}",
-        liquidity_deposit_percentage
-    );
+    (
+        amount_a.min(u64::MAX as u128) as u64,
+        amount_b.min(u64::MAX as u128) as u64,
+    )
+}
 
-    msg!("pool_token_supply: {}", pool_token_supply);
+// value of a pool token in token b as a x64 fixed point number, token a is valued at the pool
+// price so rebalancing between the tokens does not move it. 0 while the vault is empty
+pub fn calculate_share_price_x64(
+    token_a_amount: u64,
+    token_b_amount: u64,
+    sqrt_price_x64: u128,
+    pool_token_supply: u64,
+) -> u128 {
+    if pool_token_supply == 0 {
+        return 0;
+    }
 
-    let mint_to_amount = Decimal::from_u64(pool_token_supply)
-        .unwrap()
-        .checked_mul(Decimal::from_f64(liquidity_deposit_percentage).unwrap())
+    // token_a_amount * sqrt_price^2 >> 64, same steps as calculate_swap_cost
+    let step = mul_u256(token_a_amount as u128, sqrt_price_x64)
+        .shift_word_right()
+        .try_into_u128()
         .unwrap();
+    let value_x64 = mul_u256(step, sqrt_price_x64)
+        .add(U256Muldiv::new(0, token_b_amount as u128).shift_word_left());
 
-    msg!("mint_to_amount: {}", mint_to_amount.to_u128().unwrap());
+    let (quotient, _remainder) =
+        value_x64.div(U256Muldiv::new(0, pool_token_supply as u128), false);
 
-    mint_to_amount.to_u64().unwrap()
+    quotient.try_into_u128().unwrap_or(u128::MAX)
 }
 
-pub fn calculate_remove_liquidity_amount(
-    user_pool_tokens_amount: u64,
-    pool_token_supply: u64,
-    position_liquidity: u128,
+// share price of a holding after adding new shares bought at share_price_x64
+pub fn calculate_average_share_price_x64(
+    average_share_price_x64: u128,
+    shares: u64,
+    share_price_x64: u128,
+    new_shares: u64,
 ) -> u128 {
-    let percentage_of_position: f64 = user_pool_tokens_amount as f64 / pool_token_supply as f64;
+    let total_shares = shares as u128 + new_shares as u128;
+    if total_shares == 0 {
+        return 0;
+    }
 
-    Decimal::from_u128(position_liquidity)
-        .unwrap()
-        .checked_mul(Decimal::from_f64(percentage_of_position).unwrap())
+    let numerator = mul_u256(average_share_price_x64, shares as u128)
+        .add(mul_u256(share_price_x64, new_shares as u128));
+    let (quotient, _remainder) = numerator.div(U256Muldiv::new(0, total_shares), false);
+
+    quotient.try_into_u128().unwrap_or(u128::MAX)
+}
+
+// part of the withdrawn liquidity kept by the vault, rounded up in favour of the vault
+pub fn calculate_early_exit_fee(liquidity: u128, early_exit_fee_bps: u16) -> u128 {
+    let (quotient, _remainder) =
+        mul_u256(liquidity, early_exit_fee_bps as u128).div(U256Muldiv::new(0, 10_000), true);
+
+    quotient.try_into_u128().unwrap()
+}
+
+// early exit fee on a withdraw of pool_token_amount, untracked_pool_tokens are the wallet's pool
+// tokens the deposit ledger has no timestamp for and are spent first. they always pay the fee
+// but are never locked, tracked pool tokens pay it only while locked. None if locked tracked
+// pool tokens are withdrawn and there is no fee to pay instead
+pub fn calculate_withdraw_early_exit_fee(
+    liquidity: u128,
+    pool_token_amount: u64,
+    untracked_pool_tokens: u64,
+    locked: bool,
+    early_exit_fee_bps: u16,
+) -> Option<u128> {
+    let untracked_pool_tokens = untracked_pool_tokens.min(pool_token_amount);
+    let tracked_pool_tokens = pool_token_amount - untracked_pool_tokens;
+    if locked && tracked_pool_tokens > 0 && early_exit_fee_bps == 0 {
+        return None;
+    }
+
+    let charged_pool_tokens = if locked {
+        pool_token_amount
+    } else {
+        untracked_pool_tokens
+    };
+    if charged_pool_tokens == 0 {
+        return Some(0);
+    }
+
+    // liquidity backing the charged pool tokens, at most liquidity so it fits a u128
+    let (charged_liquidity, _remainder) = mul_u256(liquidity, charged_pool_tokens as u128)
+        .div(U256Muldiv::new(0, pool_token_amount as u128), true);
+
+    Some(calculate_early_exit_fee(
+        charged_liquidity.try_into_u128().unwrap(),
+        early_exit_fee_bps,
+    ))
+}
+
+// output of a swap filled entirely at sqrt_price_x64, without fees or price impact
+pub fn calculate_swap_output_at_price(amount_in: u64, sqrt_price_x64: u128, a_to_b: bool) -> u128 {
+    let sqrt_price = U256Muldiv::new(0, sqrt_price_x64);
+
+    if a_to_b {
+        // amount_in * sqrt_price^2 >> 128
+        let step = mul_u256(amount_in as u128, sqrt_price_x64)
+            .shift_word_right()
+            .try_into_u128()
+            .unwrap();
+        mul_u256(step, sqrt_price_x64)
+            .shift_word_right()
+            .try_into_u128()
+            .unwrap_or(u128::MAX)
+    } else {
+        // amount_in << 128 / sqrt_price^2
+        let (step, _remainder) = U256Muldiv::new(0, amount_in as u128)
+            .shift_word_left()
+            .div(sqrt_price, false);
+        let (quotient, _remainder) = step.shift_word_left().div(sqrt_price, false);
+        quotient.try_into_u128().unwrap_or(u128::MAX)
+    }
+}
+
+// output lost to fees and price impact compared to swapping at the pre-swap price,
+// denominated in the output token
+pub fn calculate_swap_cost(
+    amount_in: u64,
+    amount_out: u64,
+    sqrt_price_x64: u128,
+    a_to_b: bool,
+) -> u64 {
+    calculate_swap_output_at_price(amount_in, sqrt_price_x64, a_to_b)
+        .saturating_sub(amount_out as u128)
+        .min(u64::MAX as u128) as u64
+}
+
+// least output to accept from a swap: the input valued at a reference price, less the pool's
+// fee_rate (hundredths of a bps, as whirlpool stores it) and max_slippage_bps
+pub fn calculate_min_swap_output(
+    amount_in: u64,
+    reference_sqrt_price_x64: u128,
+    a_to_b: bool,
+    fee_rate: u16,
+    max_slippage_bps: u16,
+) -> u64 {
+    let amount_out = calculate_swap_output_at_price(amount_in, reference_sqrt_price_x64, a_to_b);
+
+    let (after_fee, _remainder) = mul_u256(amount_out, 1_000_000 - fee_rate as u128)
+        .div(U256Muldiv::new(0, 1_000_000), false);
+    let (min_amount_out, _remainder) = mul_u256(
+        after_fee.try_into_u128().unwrap(),
+        10_000 - max_slippage_bps.min(10_000) as u128,
+    )
+    .div(U256Muldiv::new(0, 10_000), false);
+
+    min_amount_out
+        .try_into_u128()
         .unwrap()
-        .to_u128()
+        .min(u64::MAX as u128) as u64
+}
+
+// leaves are keccak(pubkey), pairs are hashed in sorted order so proofs need no directions
+pub fn verify_merkle_proof(proof: &[[u8; 32]], root: [u8; 32], leaf: [u8; 32]) -> bool {
+    let mut computed_hash = leaf;
+    for proof_element in proof.iter() {
+        computed_hash = if computed_hash <= *proof_element {
+            hashv(&[&computed_hash, proof_element]).0
+        } else {
+            hashv(&[proof_element, &computed_hash]).0
+        };
+    }
+
+    computed_hash == root
+}
+
+// ticks per whirlpool tick array
+pub const TICK_ARRAY_SIZE: i32 = 88;
+
+// tick bounds of the whirlpool program, sqrt prices are limited to MIN_SQRT_PRICE_X64..MAX_SQRT_PRICE_X64
+pub const MIN_TICK_INDEX: i32 = -443636;
+pub const MAX_TICK_INDEX: i32 = 443636;
+
+pub fn tick_index_to_sqrt_price_x64(tick_index: i32) -> u128 {
+    sqrt_price_from_tick_index(tick_index.clamp(MIN_TICK_INDEX, MAX_TICK_INDEX))
+}
+
+// price of token_a in token_b as a Q64.64, rounded down
+pub fn tick_index_to_price_x64(tick_index: i32) -> u128 {
+    let sqrt_price_x64 = tick_index_to_sqrt_price_x64(tick_index);
+    mul_u256(sqrt_price_x64, sqrt_price_x64)
+        .shift_word_right()
+        .try_into_u128()
         .unwrap()
 }
 
+// the tick whose price range contains sqrt_price_x64
+pub fn sqrt_price_x64_to_tick_index(sqrt_price_x64: u128) -> i32 {
+    tick_index_from_sqrt_price(&sqrt_price_x64.clamp(MIN_SQRT_PRICE_X64, MAX_SQRT_PRICE_X64))
+}
+
+// lowest and highest ticks a position can use with tick_spacing
+pub fn get_min_initializable_tick_index(tick_spacing: u16) -> i32 {
+    let tick_spacing = tick_spacing as i32;
+    -(-MIN_TICK_INDEX).div_euclid(tick_spacing) * tick_spacing
+}
+
+pub fn get_max_initializable_tick_index(tick_spacing: u16) -> i32 {
+    let tick_spacing = tick_spacing as i32;
+    MAX_TICK_INDEX.div_euclid(tick_spacing) * tick_spacing
+}
+
+// nearest multiple of tick_spacing, ties round up, kept inside the initializable range
+pub fn round_tick_index_to_spacing(tick_index: i32, tick_spacing: u16) -> i32 {
+    let spacing = tick_spacing as i32;
+    let remainder = tick_index.rem_euclid(spacing);
+    let rounded = if remainder * 2 >= spacing {
+        tick_index - remainder + spacing
+    } else {
+        tick_index - remainder
+    };
+
+    rounded.clamp(
+        get_min_initializable_tick_index(tick_spacing),
+        get_max_initializable_tick_index(tick_spacing),
+    )
+}
+
+// floor of the square root
+pub fn integer_sqrt(value: u128) -> u128 {
+    if value < 2 {
+        return value;
+    }
+
+    let mut x = value;
+    let mut y = value / 2;
+    while y < x {
+        x = y;
+        y = (x + value / x) / 2;
+    }
+
+    x
+}
+
+// sqrt price of the price scaled by ratio_bps / 10000, saturates at MAX_SQRT_PRICE_X64
+pub fn scale_sqrt_price_x64_by_bps(sqrt_price_x64: u128, ratio_bps: u32) -> u128 {
+    // 32 fractional bits are plenty to land in the right tick
+    let sqrt_ratio_x32 = integer_sqrt(((ratio_bps as u128) << 64) / 10000);
+
+    match sqrt_price_x64.checked_mul(sqrt_ratio_x32) {
+        Some(scaled_sqrt_price_x64) => (scaled_sqrt_price_x64 >> 32).min(MAX_SQRT_PRICE_X64),
+        None => MAX_SQRT_PRICE_X64,
+    }
+}
+
+// ticks for prices lower_bps below and upper_bps above sqrt_price_x64, snapped to tick_spacing
+pub fn calculate_tick_range_from_bps_offsets(
+    sqrt_price_x64: u128,
+    lower_bps: u16,
+    upper_bps: u16,
+    tick_spacing: u16,
+) -> Option<(i32, i32)> {
+    let lower_ratio_bps = 10000 - (lower_bps as u32).min(10000);
+    let upper_ratio_bps = 10000 + upper_bps as u32;
+
+    snap_tick_range(
+        sqrt_price_x64_to_tick_index(scale_sqrt_price_x64_by_bps(sqrt_price_x64, lower_ratio_bps)),
+        sqrt_price_x64_to_tick_index(scale_sqrt_price_x64_by_bps(sqrt_price_x64, upper_ratio_bps)),
+        tick_spacing,
+    )
+}
+
+// a range order sits entirely on one side of the price, a sell order only holds token a and
+// starts one tick_spacing above the price, a buy order only holds token b and ends at or below it
+pub fn calculate_range_order_tick_range(
+    tick_index_current: i32,
+    tick_spacing: u16,
+    width_ticks: i32,
+    sell_a: bool,
+) -> Option<(i32, i32)> {
+    let spacing = tick_spacing as i32;
+    let width = (width_ticks / spacing).max(1) * spacing;
+    let tick_index_floor = tick_index_current - tick_index_current.rem_euclid(spacing);
+
+    let (lower_tick_index, upper_tick_index) = if sell_a {
+        (
+            tick_index_floor + spacing,
+            tick_index_floor + spacing + width,
+        )
+    } else {
+        (tick_index_floor - width, tick_index_floor)
+    };
+
+    if lower_tick_index < get_min_initializable_tick_index(tick_spacing)
+        || upper_tick_index > get_max_initializable_tick_index(tick_spacing)
+    {
+        return None;
+    }
+
+    Some((lower_tick_index, upper_tick_index))
+}
+
+// the order has not been reached yet and still only holds the token it is selling
+pub fn range_order_resting(
+    sell_a: bool,
+    tick_index_current: i32,
+    tick_index_lower: i32,
+    tick_index_upper: i32,
+) -> bool {
+    if sell_a {
+        tick_index_current < tick_index_lower
+    } else {
+        tick_index_current >= tick_index_upper
+    }
+}
+
+// the price has crossed through the whole order, only the token bought is left
+pub fn range_order_filled(
+    sell_a: bool,
+    tick_index_current: i32,
+    tick_index_lower: i32,
+    tick_index_upper: i32,
+) -> bool {
+    range_order_resting(
+        !sell_a,
+        tick_index_current,
+        tick_index_lower,
+        tick_index_upper,
+    )
+}
+
+// ticks are log prices, so the summed squared tick changes over the seconds they took is the
+// variance per second. scaled to horizon_seconds and rooted it is the realized volatility in
+// ticks, a move spread over a long gap between observations counts for less than a quick one
+pub fn calculate_realized_volatility_ticks(
+    observations: &[(i64, i32)],
+    horizon_seconds: i64,
+) -> u64 {
+    let mut sum_of_squares: u128 = 0;
+    let mut total_seconds: u128 = 0;
+
+    for pair in observations.windows(2) {
+        let seconds = (pair[1].0 - pair[0].0).max(0) as u128;
+        let change = (pair[1].1 as i64 - pair[0].1 as i64).unsigned_abs() as u128;
+        sum_of_squares += change * change;
+        total_seconds += seconds;
+    }
+
+    if total_seconds == 0 {
+        return 0;
+    }
+
+    integer_sqrt(sum_of_squares * horizon_seconds.max(0) as u128 / total_seconds) as u64
+}
+
+// each (timestamp, tick_index) holds until the next observation and the last one until now, so
+// an observation taken at now has no weight and the price a crank sees can not move the average
+pub fn calculate_time_weighted_tick_index(observations: &[(i64, i32)], now: i64) -> Option<i32> {
+    let mut weighted_ticks: i128 = 0;
+    let mut total_seconds: i128 = 0;
+
+    for (i, (timestamp, tick_index)) in observations.iter().enumerate() {
+        let until = observations.get(i + 1).map_or(now, |next| next.0);
+        let seconds = (until - timestamp).max(0) as i128;
+        weighted_ticks += seconds * *tick_index as i128;
+        total_seconds += seconds;
+    }
+
+    if total_seconds == 0 {
+        return None;
+    }
+
+    Some(weighted_ticks.div_euclid(total_seconds) as i32)
+}
+
+// number of ticks a price has to move up to change by bps
+pub fn bps_to_tick_delta(bps: u16) -> i32 {
+    let sqrt_price_x64 = tick_index_to_sqrt_price_x64(0);
+    sqrt_price_x64_to_tick_index(scale_sqrt_price_x64_by_bps(
+        sqrt_price_x64,
+        10000 + bps as u32,
+    ))
+}
+
+// splits a total width into (lower_bps, upper_bps), a positive skew moves the range above the price
+// and +-10000 puts all of it on one side
+pub fn calculate_bps_offsets_from_width(width_bps: u16, skew_bps: i16) -> (u16, u16) {
+    let skew_bps = skew_bps.clamp(-10000, 10000) as i64;
+    let upper_bps = (width_bps as i64 * (10000 + skew_bps) / 20000) as u16;
+
+    (width_bps - upper_bps, upper_bps)
+}
+
+// snaps both ends of a range to tick_spacing, None if nothing is left of the range
+pub fn snap_tick_range(
+    lower_tick_index: i32,
+    upper_tick_index: i32,
+    tick_spacing: u16,
+) -> Option<(i32, i32)> {
+    let lower_tick_index = round_tick_index_to_spacing(lower_tick_index, tick_spacing);
+    let upper_tick_index = round_tick_index_to_spacing(upper_tick_index, tick_spacing);
+
+    if lower_tick_index >= upper_tick_index {
+        return None;
+    }
+
+    Some((lower_tick_index, upper_tick_index))
+}
+
+// start tick index of the tick array that contains tick_index, rounds toward negative infinity
+pub fn get_tick_array_start_index(tick_index: i32, tick_spacing: u16) -> i32 {
+    let ticks_in_array = TICK_ARRAY_SIZE * tick_spacing as i32;
+    tick_index.div_euclid(ticks_in_array) * ticks_in_array
+}
+
 // lifted from spl-token
 pub fn amount_to_ui_amount(amount: u64, decimals: u8) -> f64 {
     amount as f64 / 10_usize.pow(decimals as u32) as f64
@@ -239,42 +811,777 @@ mod tests {
         assert_eq!(token_b_percentage, 51.016475992055696);
     }
 
+    #[test]
+    fn rebalance_swap_b_to_a() {
+        let (a_to_b, amount) = calculate_rebalance_swap(
+            31.1597,
+            35.278339,
+            39.7383,
+            2_500_000_000,
+            150_000_000,
+            9,
+            6,
+        );
+        assert!(!a_to_b);
+        assert_eq!(amount, 29662907);
+    }
+
+    #[test]
+    fn rebalance_swap_a_to_b() {
+        let (a_to_b, amount) =
+            calculate_rebalance_swap(31.1597, 35.278339, 39.7383, 2_500_000_000, 45_000_000, 9, 6);
+        assert!(a_to_b);
+        assert_eq!(amount, 624667215);
+    }
+
+    #[test]
+    fn single_sided_swap_amount_token_a() {
+        let swap_amount =
+            calculate_single_sided_swap_amount(1_000_000_000, true, 30.3722, 32.831445, 39.7383);
+        assert_eq!(swap_amount, 295452142);
+    }
+
+    #[test]
+    fn single_sided_swap_amount_token_b() {
+        let swap_amount =
+            calculate_single_sided_swap_amount(50_000_000, false, 30.3722, 32.831445, 39.7383);
+        assert_eq!(swap_amount, 35227392);
+    }
+
     #[test]
     fn sqrt_to_f64() {
         let price = sqrt_price_x64_to_price(1844674407370955161, 6, 9);
         assert_eq!(price, 9.999999991044025);
     }
 
+    // reference vectors from uniswap v3-periphery LiquidityAmounts.spec.ts, re-encoded as x64
+    // sqrt(100/110), sqrt(1/1) and sqrt(110/100)
+    const SQRT_PRICE_LOWER_X64: u128 = 17588280367669894507;
+    const SQRT_PRICE_ONE_X64: u128 = 18446744073709551616;
+    const SQRT_PRICE_UPPER_X64: u128 = 19347108404436883958;
+
+    #[test]
+    fn liquidity_from_token_a() {
+        let liquidity = get_liquidity_from_token_a(100, SQRT_PRICE_LOWER_X64, SQRT_PRICE_UPPER_X64);
+        assert_eq!(liquidity, 1048);
+    }
+
+    #[test]
+    fn liquidity_from_token_b() {
+        let liquidity = get_liquidity_from_token_b(200, SQRT_PRICE_LOWER_X64, SQRT_PRICE_UPPER_X64);
+        assert_eq!(liquidity, 2097);
+    }
+
+    #[test]
+    fn liquidity_for_amounts_price_inside() {
+        let liquidity = get_liquidity_for_amounts(
+            100,
+            200,
+            SQRT_PRICE_LOWER_X64,
+            SQRT_PRICE_ONE_X64,
+            SQRT_PRICE_UPPER_X64,
+        );
+        assert_eq!(liquidity, 2148);
+    }
+
+    #[test]
+    fn liquidity_for_amounts_price_below() {
+        // sqrt(99/110)
+        let liquidity = get_liquidity_for_amounts(
+            100,
+            200,
+            SQRT_PRICE_LOWER_X64,
+            17500118006140547654,
+            SQRT_PRICE_UPPER_X64,
+        );
+        assert_eq!(liquidity, 1048);
+    }
+
+    #[test]
+    fn liquidity_for_amounts_price_above() {
+        // sqrt(111/100)
+        let liquidity = get_liquidity_for_amounts(
+            100,
+            200,
+            SQRT_PRICE_LOWER_X64,
+            19434850842809205733,
+            SQRT_PRICE_UPPER_X64,
+        );
+        assert_eq!(liquidity, 2097);
+    }
+
+    #[test]
+    fn liquidity_for_amounts_price_at_lower_boundary() {
+        let liquidity = get_liquidity_for_amounts(
+            100,
+            200,
+            SQRT_PRICE_LOWER_X64,
+            SQRT_PRICE_LOWER_X64,
+            SQRT_PRICE_UPPER_X64,
+        );
+        assert_eq!(liquidity, 1048);
+    }
+
+    #[test]
+    fn liquidity_for_amounts_price_at_upper_boundary() {
+        let liquidity = get_liquidity_for_amounts(
+            100,
+            200,
+            SQRT_PRICE_LOWER_X64,
+            SQRT_PRICE_UPPER_X64,
+            SQRT_PRICE_UPPER_X64,
+        );
+        assert_eq!(liquidity, 2097);
+    }
+
+    #[test]
+    fn scale_liquidity_to_balances_within_balances() {
+        let liquidity = scale_liquidity_to_balances(1_000, 100, 200, 100, 250);
+        assert_eq!(liquidity, 1_000);
+    }
+
+    #[test]
+    fn scale_liquidity_to_balances_short_one_unit() {
+        let liquidity = scale_liquidity_to_balances(1_000, 101, 200, 100, 200);
+        assert_eq!(liquidity, 990);
+    }
+
+    #[test]
+    fn scale_liquidity_to_balances_uses_the_scarcer_token() {
+        let liquidity = scale_liquidity_to_balances(1_000, 200, 400, 150, 100);
+        assert_eq!(liquidity, 250);
+    }
+
+    #[test]
+    fn calculate_liquidity_for_pool_tokens_first_deposit() {
+        let liquidity = calculate_liquidity_for_pool_tokens(192600016187, 0, 0, true);
+        assert_eq!(192600016187, liquidity);
+    }
+
+    #[test]
+    fn calculate_liquidity_for_pool_tokens_rounds_up() {
+        let liquidity = calculate_liquidity_for_pool_tokens(1, 3, 10, true);
+        assert_eq!(4, liquidity);
+    }
+
+    #[test]
+    fn calculate_liquidity_for_pool_tokens_half_of_supply() {
+        let liquidity =
+            calculate_liquidity_for_pool_tokens(98290094549, 196580189098, 393160378196, true);
+        assert_eq!(196580189098, liquidity);
+    }
+
+    #[test]
+    fn calculate_liquidity_for_pool_tokens_rounds_down() {
+        let liquidity = calculate_liquidity_for_pool_tokens(1, 3, 10, false);
+        assert_eq!(3, liquidity);
+    }
+
+    #[test]
+    fn calculate_pool_tokens_for_liquidity_first_deposit() {
+        let pool_tokens = calculate_pool_tokens_for_liquidity(192600016187, 0, 0, false).unwrap();
+        assert_eq!(192600016187, pool_tokens);
+    }
+
+    #[test]
+    fn calculate_pool_tokens_for_liquidity_half_of_position() {
+        let pool_tokens =
+            calculate_pool_tokens_for_liquidity(196580189098, 393160378196, 196580189098, false)
+                .unwrap();
+        assert_eq!(98290094549, pool_tokens);
+    }
+
+    #[test]
+    fn calculate_pool_tokens_for_liquidity_rounding() {
+        assert_eq!(
+            Some(3),
+            calculate_pool_tokens_for_liquidity(1, 3, 10, false)
+        );
+        assert_eq!(Some(4), calculate_pool_tokens_for_liquidity(1, 3, 10, true));
+    }
+
+    #[test]
+    fn calculate_pool_tokens_for_liquidity_overflow() {
+        assert_eq!(
+            None,
+            calculate_pool_tokens_for_liquidity(u64::MAX as u128 + 1, 0, 0, false)
+        );
+        assert_eq!(
+            None,
+            calculate_pool_tokens_for_liquidity(u128::MAX, 1, u64::MAX, false)
+        );
+        assert_eq!(
+            Some(u64::MAX),
+            calculate_pool_tokens_for_liquidity(u64::MAX as u128, 10, 10, false)
+        );
+    }
+
+    #[test]
+    fn share_price_empty_vault() {
+        assert_eq!(0, calculate_share_price_x64(1000, 1000, 1 << 64, 0));
+    }
+
+    #[test]
+    fn share_price_two_token_b_per_pool_token() {
+        assert_eq!(
+            36893488147419103232,
+            calculate_share_price_x64(0, 200, 1 << 64, 100)
+        );
+    }
+
+    #[test]
+    fn share_price_values_token_a_at_the_pool_price() {
+        // sqrt price 2, one token a is worth four token b
+        assert_eq!(
+            calculate_share_price_x64(0, 400, 2 << 64, 100),
+            calculate_share_price_x64(100, 0, 2 << 64, 100)
+        );
+        assert_eq!(
+            calculate_share_price_x64(50, 200, 2 << 64, 100),
+            calculate_share_price_x64(100, 0, 2 << 64, 100)
+        );
+    }
+
+    #[test]
+    fn amounts_for_liquidity_in_range() {
+        // one tick either side of price 1
+        let (amount_a, amount_b) = get_amounts_for_liquidity(
+            1_000_000_000,
+            sqrt_price_from_tick_index(-1),
+            1 << 64,
+            sqrt_price_from_tick_index(1),
+        );
+        assert!(amount_a > 0 && amount_b > 0);
+        assert!(amount_a.abs_diff(amount_b) <= 1);
+    }
+
+    #[test]
+    fn amounts_for_liquidity_out_of_range() {
+        let sqrt_price_lower_x64 = sqrt_price_from_tick_index(-64);
+        let sqrt_price_upper_x64 = sqrt_price_from_tick_index(64);
+
+        let (amount_a, amount_b) = get_amounts_for_liquidity(
+            1_000_000_000,
+            sqrt_price_lower_x64,
+            sqrt_price_lower_x64 - 1,
+            sqrt_price_upper_x64,
+        );
+        assert!(amount_a > 0);
+        assert_eq!(0, amount_b);
+
+        let (amount_a, amount_b) = get_amounts_for_liquidity(
+            1_000_000_000,
+            sqrt_price_lower_x64,
+            sqrt_price_upper_x64 + 1,
+            sqrt_price_upper_x64,
+        );
+        assert_eq!(0, amount_a);
+        assert!(amount_b > 0);
+    }
+
+    #[test]
+    fn average_share_price_first_deposit() {
+        assert_eq!(
+            36893488147419103232,
+            calculate_average_share_price_x64(0, 0, 36893488147419103232, 100)
+        );
+    }
+
+    #[test]
+    fn average_share_price_weighted_by_shares() {
+        // 100 shares at 1 and 100 shares at 3
+        assert_eq!(
+            36893488147419103232,
+            calculate_average_share_price_x64(18446744073709551616, 100, 55340232221128654848, 100)
+        );
+    }
+
+    #[test]
+    fn early_exit_fee() {
+        assert_eq!(50, calculate_early_exit_fee(10_000, 50));
+        assert_eq!(1, calculate_early_exit_fee(1, 50));
+        assert_eq!(0, calculate_early_exit_fee(10_000, 0));
+    }
+
+    #[test]
+    fn withdraw_early_exit_fee_unlocked_tracked_pool_tokens() {
+        assert_eq!(
+            Some(0),
+            calculate_withdraw_early_exit_fee(10_000, 100, 0, false, 50)
+        );
+    }
+
+    #[test]
+    fn withdraw_early_exit_fee_locked_tracked_pool_tokens() {
+        assert_eq!(
+            Some(50),
+            calculate_withdraw_early_exit_fee(10_000, 100, 0, true, 50)
+        );
+        assert_eq!(
+            None,
+            calculate_withdraw_early_exit_fee(10_000, 100, 0, true, 0)
+        );
+    }
+
+    #[test]
+    fn withdraw_early_exit_fee_legacy_holder_is_never_locked() {
+        // pool tokens minted before the ledger existed have no deposit timestamp
+        assert_eq!(
+            Some(0),
+            calculate_withdraw_early_exit_fee(10_000, 100, 100, true, 0)
+        );
+        assert_eq!(
+            Some(0),
+            calculate_withdraw_early_exit_fee(10_000, 100, 100, false, 0)
+        );
+        assert_eq!(
+            Some(50),
+            calculate_withdraw_early_exit_fee(10_000, 100, 100, false, 50)
+        );
+    }
+
+    #[test]
+    fn withdraw_early_exit_fee_transferred_pool_tokens_pay_the_fee() {
+        // old unlocked shares plus pool tokens just received from another wallet, the received
+        // ones are spent first and pay the fee
+        assert_eq!(
+            Some(50),
+            calculate_withdraw_early_exit_fee(20_000, 200, 100, false, 50)
+        );
+        assert_eq!(
+            Some(50),
+            calculate_withdraw_early_exit_fee(10_000, 100, 150, false, 50)
+        );
+    }
+
+    #[test]
+    fn withdraw_early_exit_fee_transferred_pool_tokens_with_locked_shares() {
+        // received pool tokens do not unlock the shares deposited inside the lock
+        assert_eq!(
+            None,
+            calculate_withdraw_early_exit_fee(20_000, 200, 100, true, 0)
+        );
+        assert_eq!(
+            Some(0),
+            calculate_withdraw_early_exit_fee(10_000, 100, 100, true, 0)
+        );
+        assert_eq!(
+            Some(100),
+            calculate_withdraw_early_exit_fee(20_000, 200, 100, true, 50)
+        );
+    }
+
+    #[test]
+    fn merkle_proof_two_leaves() {
+        let leaf_a = hashv(&[&[1; 32]]).0;
+        let leaf_b = hashv(&[&[2; 32]]).0;
+        let root = if leaf_a <= leaf_b {
+            hashv(&[&leaf_a, &leaf_b]).0
+        } else {
+            hashv(&[&leaf_b, &leaf_a]).0
+        };
+
+        assert!(verify_merkle_proof(&[leaf_b], root, leaf_a));
+        assert!(verify_merkle_proof(&[leaf_a], root, leaf_b));
+        assert!(!verify_merkle_proof(&[leaf_b], root, hashv(&[&[3; 32]]).0));
+    }
+
+    #[test]
+    fn merkle_proof_single_leaf() {
+        let leaf = hashv(&[&[1; 32]]).0;
+        assert!(verify_merkle_proof(&[], leaf, leaf));
+    }
+
+    #[test]
+    fn swap_cost_a_to_b() {
+        // price 4
+        let sqrt_price_x64 = 36893488147419103232;
+        assert_eq!(10, calculate_swap_cost(1000, 3990, sqrt_price_x64, true));
+    }
+
+    #[test]
+    fn swap_cost_b_to_a() {
+        let sqrt_price_x64 = 36893488147419103232;
+        assert_eq!(5, calculate_swap_cost(4000, 995, sqrt_price_x64, false));
+    }
+
+    #[test]
+    fn swap_cost_better_than_price() {
+        assert_eq!(0, calculate_swap_cost(1000, 1001, SQRT_PRICE_ONE_X64, true));
+    }
+
+    #[test]
+    fn min_swap_output_a_to_b() {
+        let sqrt_price_x64 = 36893488147419103232;
+        assert_eq!(
+            4000,
+            calculate_min_swap_output(1000, sqrt_price_x64, true, 0, 0)
+        );
+        assert_eq!(
+            3948,
+            calculate_min_swap_output(1000, sqrt_price_x64, true, 3000, 100)
+        );
+    }
+
+    #[test]
+    fn min_swap_output_b_to_a() {
+        let sqrt_price_x64 = 36893488147419103232;
+        assert_eq!(
+            987,
+            calculate_min_swap_output(4000, sqrt_price_x64, false, 3000, 100)
+        );
+    }
+
+    #[test]
+    fn min_swap_output_full_slippage() {
+        assert_eq!(
+            0,
+            calculate_min_swap_output(1000, SQRT_PRICE_ONE_X64, true, 0, 10_000)
+        );
+    }
+
+    #[test]
+    fn tick_array_start_index_positive() {
+        assert_eq!(0, get_tick_array_start_index(0, 64));
+        assert_eq!(0, get_tick_array_start_index(5631, 64));
+        assert_eq!(5632, get_tick_array_start_index(5632, 64));
+        assert_eq!(880, get_tick_array_start_index(1000, 10));
+    }
+
+    #[test]
+    fn tick_array_start_index_negative() {
+        assert_eq!(-5632, get_tick_array_start_index(-1, 64));
+        assert_eq!(-5632, get_tick_array_start_index(-5632, 64));
+        assert_eq!(-11264, get_tick_array_start_index(-5633, 64));
+        assert_eq!(-88, get_tick_array_start_index(-88, 1));
+    }
+
+    #[test]
+    fn tick_index_to_price_at_zero() {
+        assert_eq!(SQRT_PRICE_ONE_X64, tick_index_to_sqrt_price_x64(0));
+        assert_eq!(SQRT_PRICE_ONE_X64, tick_index_to_price_x64(0));
+    }
+
+    #[test]
+    fn tick_index_to_price_negative() {
+        // 1.0001^-10000 ~= 0.3679
+        let price_x64 = tick_index_to_price_x64(-10000);
+        let price = price_x64 as f64 / SQRT_PRICE_ONE_X64 as f64;
+        assert!((price - 0.367898).abs() < 0.000001);
+        assert!(tick_index_to_price_x64(-10001) < price_x64);
+    }
+
+    #[test]
+    fn tick_index_to_price_extremes() {
+        assert_eq!(
+            MIN_SQRT_PRICE_X64,
+            tick_index_to_sqrt_price_x64(MIN_TICK_INDEX)
+        );
+        assert_eq!(
+            MAX_SQRT_PRICE_X64,
+            tick_index_to_sqrt_price_x64(MAX_TICK_INDEX)
+        );
+        assert_eq!(MAX_SQRT_PRICE_X64, tick_index_to_sqrt_price_x64(i32::MAX));
+
+        // the largest price still fits in a u128
+        assert!(
+            tick_index_to_price_x64(MAX_TICK_INDEX) > tick_index_to_price_x64(MAX_TICK_INDEX - 1)
+        );
+        // below 2^-64 the price can't be represented
+        assert_eq!(1, tick_index_to_price_x64(MIN_TICK_INDEX));
+    }
+
+    #[test]
+    fn sqrt_price_to_tick_index_round_trip() {
+        for tick_index in [
+            MIN_TICK_INDEX,
+            -100000,
+            -5633,
+            -1,
+            0,
+            1,
+            5632,
+            100000,
+            MAX_TICK_INDEX - 1,
+        ] {
+            let sqrt_price_x64 = tick_index_to_sqrt_price_x64(tick_index);
+            assert_eq!(tick_index, sqrt_price_x64_to_tick_index(sqrt_price_x64));
+            // anything below the next tick's price is still in this tick
+            let next_sqrt_price_x64 = tick_index_to_sqrt_price_x64(tick_index + 1);
+            assert_eq!(
+                tick_index,
+                sqrt_price_x64_to_tick_index(next_sqrt_price_x64 - 1)
+            );
+        }
+    }
+
+    #[test]
+    fn sqrt_price_to_tick_index_clamped() {
+        assert_eq!(MIN_TICK_INDEX, sqrt_price_x64_to_tick_index(0));
+        assert_eq!(MAX_TICK_INDEX, sqrt_price_x64_to_tick_index(u128::MAX));
+    }
+
+    #[test]
+    fn round_tick_index_positive() {
+        assert_eq!(0, round_tick_index_to_spacing(31, 64));
+        assert_eq!(64, round_tick_index_to_spacing(32, 64));
+        assert_eq!(128, round_tick_index_to_spacing(128, 64));
+        assert_eq!(5, round_tick_index_to_spacing(5, 1));
+    }
+
+    #[test]
+    fn round_tick_index_negative() {
+        assert_eq!(0, round_tick_index_to_spacing(-31, 64));
+        assert_eq!(0, round_tick_index_to_spacing(-32, 64));
+        assert_eq!(-64, round_tick_index_to_spacing(-33, 64));
+        assert_eq!(-12800, round_tick_index_to_spacing(-12790, 64));
+    }
+
+    #[test]
+    fn round_tick_index_extremes() {
+        assert_eq!(-443584, get_min_initializable_tick_index(64));
+        assert_eq!(443584, get_max_initializable_tick_index(64));
+        assert_eq!(MIN_TICK_INDEX, get_min_initializable_tick_index(1));
+        assert_eq!(MAX_TICK_INDEX, get_max_initializable_tick_index(1));
+
+        assert_eq!(-443584, round_tick_index_to_spacing(MIN_TICK_INDEX, 64));
+        assert_eq!(443584, round_tick_index_to_spacing(MAX_TICK_INDEX, 64));
+        assert_eq!(-443584, round_tick_index_to_spacing(i32::MIN / 2, 64));
+    }
+
+    #[test]
+    fn snap_tick_range_aligns() {
+        assert_eq!(Some((-12800, -11200)), snap_tick_range(-12790, -11210, 64));
+        assert_eq!(None, snap_tick_range(10, 20, 64));
+        assert_eq!(None, snap_tick_range(128, 64, 64));
+    }
+
+    #[test]
+    fn integer_sqrt_floors() {
+        assert_eq!(0, integer_sqrt(0));
+        assert_eq!(1, integer_sqrt(3));
+        assert_eq!(2, integer_sqrt(4));
+        assert_eq!(1 << 63, integer_sqrt(1 << 126));
+        assert_eq!(u64::MAX as u128, integer_sqrt(u128::MAX));
+    }
+
+    #[test]
+    fn scale_sqrt_price_by_bps() {
+        assert_eq!(
+            SQRT_PRICE_ONE_X64,
+            scale_sqrt_price_x64_by_bps(SQRT_PRICE_ONE_X64, 10000)
+        );
+        // price 4 -> sqrt price 2
+        assert_eq!(
+            2 * SQRT_PRICE_ONE_X64,
+            scale_sqrt_price_x64_by_bps(SQRT_PRICE_ONE_X64, 40000)
+        );
+        assert_eq!(0, scale_sqrt_price_x64_by_bps(SQRT_PRICE_ONE_X64, 0));
+        assert_eq!(
+            MAX_SQRT_PRICE_X64,
+            scale_sqrt_price_x64_by_bps(MAX_SQRT_PRICE_X64, 20000)
+        );
+    }
+
+    #[test]
+    fn tick_range_from_bps_offsets() {
+        // 0.95 and 1.05 are ticks -512.96 and 487.92
+        assert_eq!(
+            Some((-513, 487)),
+            calculate_tick_range_from_bps_offsets(SQRT_PRICE_ONE_X64, 500, 500, 1)
+        );
+        assert_eq!(
+            Some((-512, 512)),
+            calculate_tick_range_from_bps_offsets(SQRT_PRICE_ONE_X64, 500, 500, 64)
+        );
+    }
+
+    #[test]
+    fn tick_range_from_bps_offsets_negative_price_ticks() {
+        let sqrt_price_x64 = tick_index_to_sqrt_price_x64(-12000);
+        let (lower, upper) =
+            calculate_tick_range_from_bps_offsets(sqrt_price_x64, 500, 500, 64).unwrap();
+        assert_eq!((-12544, -11520), (lower, upper));
+    }
+
+    #[test]
+    fn tick_range_from_bps_offsets_extremes() {
+        // a 100% lower offset is a price of 0
+        assert_eq!(
+            Some((-443584, 20224)),
+            calculate_tick_range_from_bps_offsets(SQRT_PRICE_ONE_X64, 10000, u16::MAX, 64)
+        );
+        assert_eq!(
+            Some((443520, 443584)),
+            calculate_tick_range_from_bps_offsets(MAX_SQRT_PRICE_X64, 100, 100, 64)
+        );
+        assert_eq!(
+            None,
+            calculate_tick_range_from_bps_offsets(SQRT_PRICE_ONE_X64, 0, 0, 64)
+        );
+    }
+
+    #[test]
+    fn range_order_tick_ranges() {
+        // sell above the price, buy at or below it
+        assert_eq!(
+            Some((-11968, -11328)),
+            calculate_range_order_tick_range(-11990, 64, 640, true)
+        );
+        assert_eq!(
+            Some((-12672, -12032)),
+            calculate_range_order_tick_range(-11990, 64, 640, false)
+        );
+        // on a tick_spacing boundary
+        assert_eq!(
+            Some((-11968, -11328)),
+            calculate_range_order_tick_range(-12032, 64, 640, true)
+        );
+        assert_eq!(
+            Some((-12672, -12032)),
+            calculate_range_order_tick_range(-12032, 64, 640, false)
+        );
+        // at least one tick_spacing wide
+        assert_eq!(
+            Some((-11968, -11904)),
+            calculate_range_order_tick_range(-11990, 64, 10, true)
+        );
+        // no room left next to the price
+        assert_eq!(
+            None,
+            calculate_range_order_tick_range(443500, 64, 128, true)
+        );
+        assert_eq!(
+            None,
+            calculate_range_order_tick_range(-443500, 64, 128, false)
+        );
+    }
+
+    #[test]
+    fn range_order_resting_and_filled() {
+        let (lower, upper) = calculate_range_order_tick_range(-11990, 64, 640, true).unwrap();
+        assert!(range_order_resting(true, -11990, lower, upper));
+        assert!(!range_order_filled(true, -11990, lower, upper));
+        // partially filled
+        assert!(!range_order_resting(true, lower, lower, upper));
+        assert!(!range_order_filled(true, lower, lower, upper));
+        assert!(range_order_filled(true, upper, lower, upper));
+
+        let (lower, upper) = calculate_range_order_tick_range(-11990, 64, 640, false).unwrap();
+        assert!(range_order_resting(false, -11990, lower, upper));
+        assert!(range_order_resting(false, upper, lower, upper));
+        assert!(!range_order_filled(false, upper - 1, lower, upper));
+        assert!(range_order_filled(false, lower - 1, lower, upper));
+    }
+
+    #[test]
+    fn realized_volatility_calm() {
+        assert_eq!(0, calculate_realized_volatility_ticks(&[], 3600));
+        assert_eq!(0, calculate_realized_volatility_ticks(&[(0, -100)], 3600));
+        assert_eq!(
+            0,
+            calculate_realized_volatility_ticks(&[(0, -100), (60, -100), (120, -100)], 3600)
+        );
+    }
+
+    #[test]
+    fn realized_volatility_moves() {
+        // 3, 4 -> 5 over the whole horizon
+        assert_eq!(
+            5,
+            calculate_realized_volatility_ticks(&[(0, 0), (60, 3), (120, -1)], 120)
+        );
+        assert_eq!(
+            20,
+            calculate_realized_volatility_ticks(
+                &[
+                    (0, -12000),
+                    (60, -11990),
+                    (120, -12000),
+                    (180, -11990),
+                    (240, -12000)
+                ],
+                240
+            )
+        );
+        assert_eq!(
+            (MAX_TICK_INDEX - MIN_TICK_INDEX) as u64,
+            calculate_realized_volatility_ticks(&[(0, MIN_TICK_INDEX), (60, MAX_TICK_INDEX)], 60)
+        );
+    }
+
+    #[test]
+    fn realized_volatility_weights_by_time() {
+        // 31 with the same moves 60 seconds apart, a tenth of the variance per second at 600
+        assert_eq!(
+            31,
+            calculate_realized_volatility_ticks(&[(0, 0), (60, 30), (120, 20)], 120)
+        );
+        assert_eq!(
+            10,
+            calculate_realized_volatility_ticks(&[(0, 0), (600, 30), (1200, 20)], 120)
+        );
+        // scales with the root of the horizon
+        assert_eq!(
+            20,
+            calculate_realized_volatility_ticks(&[(0, 0), (60, 10)], 240)
+        );
+    }
+
+    #[test]
+    fn realized_volatility_needs_elapsed_time() {
+        assert_eq!(
+            0,
+            calculate_realized_volatility_ticks(&[(60, 0), (60, 1000)], 3600)
+        );
+    }
+
     #[test]
-    fn calculate_pool_tokens_first_deposit() {
-        let mint_to_amount = calculate_pool_token_mint_to_amount(192600016187, 192600016187, 0);
-        assert_eq!(192600016187, mint_to_amount);
+    fn time_weighted_tick_index_needs_elapsed_time() {
+        assert_eq!(None, calculate_time_weighted_tick_index(&[], 1000));
+        assert_eq!(
+            None,
+            calculate_time_weighted_tick_index(&[(1000, 50)], 1000)
+        );
     }
 
     #[test]
-    fn calculate_pool_tokens_two_equal_deposits() {
-        let mint_to_amount =
-            calculate_pool_token_mint_to_amount(192600016187, 192600016187, 192600016187);
-        assert_eq!(192600016187, mint_to_amount);
+    fn time_weighted_tick_index_ignores_the_latest_observation() {
+        // the crank's own observation at now carries no weight
+        assert_eq!(
+            Some(-100),
+            calculate_time_weighted_tick_index(&[(0, -100), (600, 5000)], 600)
+        );
     }
 
     #[test]
-    fn calculate_pool_tokens_two_deposits_second_half_of_first() {
-        let mint_to_amount =
-            calculate_pool_token_mint_to_amount(98290094549, 196580189098, 196580189098);
-        assert_eq!(98290094549, mint_to_amount);
+    fn time_weighted_tick_index_weights_by_time() {
+        // 60 seconds at 0, 180 seconds at 100
+        assert_eq!(
+            Some(75),
+            calculate_time_weighted_tick_index(&[(0, 0), (60, 100)], 240)
+        );
+        // rounds toward negative infinity
+        assert_eq!(
+            Some(-1),
+            calculate_time_weighted_tick_index(&[(0, 0), (60, -1)], 120)
+        );
     }
 
     #[test]
-    fn calculate_pool_tokens_three_deposits_first_and_third_equal_second_half_of_first() {
-        let mint_to_amount =
-            calculate_pool_token_mint_to_amount(196580189098, 294870283647, 294870283647);
-        assert_eq!(196580189098, mint_to_amount);
+    fn bps_to_tick_deltas() {
+        assert_eq!(0, bps_to_tick_delta(0));
+        assert_eq!(99, bps_to_tick_delta(100));
+        assert_eq!(487, bps_to_tick_delta(500));
+        assert_eq!(6931, bps_to_tick_delta(10000));
     }
 
     #[test]
-    fn calculate_liquidity_removed() {
-        let liquidity_removed = calculate_remove_liquidity_amount(10, 100, 1000);
-        assert_eq!(100, liquidity_removed);
+    fn bps_offsets_from_width() {
+        assert_eq!((500, 500), calculate_bps_offsets_from_width(1000, 0));
+        assert_eq!((0, 1000), calculate_bps_offsets_from_width(1000, 10000));
+        assert_eq!((1000, 0), calculate_bps_offsets_from_width(1000, -10000));
+        assert_eq!((750, 250), calculate_bps_offsets_from_width(1000, -5000));
+        assert_eq!((0, 1000), calculate_bps_offsets_from_width(1000, i16::MAX));
     }
 }
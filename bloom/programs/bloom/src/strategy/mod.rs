@@ -0,0 +1,513 @@
+use anchor_lang::prelude::*;
+use whirlpools::state::Whirlpool;
+
+use crate::errors::BloomErrorCode;
+use crate::instructions::{position_in_range, RangeSpec};
+use crate::math;
+
+// how a vault picks its next range, Manual leaves it to the crank
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    Manual,
+    // centered on the price
+    FixedWidth {
+        width_bps: u16,
+    },
+    // see math::calculate_bps_offsets_from_width for skew_bps
+    Skewed {
+        width_bps: u16,
+        skew_bps: i16,
+    },
+    // only follows price out of the trail_up side, the position waits out moves the other way
+    Trailing {
+        width_bps: u16,
+        trail_up: bool,
+    },
+    // width narrows from start_width_bps to end_width_bps over decay_seconds since the last rebalance
+    TimeDecay {
+        start_width_bps: u16,
+        end_width_bps: u16,
+        decay_seconds: u32,
+    },
+    // width is multiplier_bps / 10000 times the realized volatility of PriceObservations,
+    // kept between min_width_bps and max_width_bps
+    Volatility {
+        multiplier_bps: u16,
+        min_width_bps: u16,
+        max_width_bps: u16,
+    },
+    // program returns (lower_tick_index, upper_tick_index) from get_target_range, see
+    // check_external_tick_range for the bounds bloom still enforces
+    External {
+        program: Pubkey,
+        min_width_bps: u16,
+        max_width_bps: u16,
+        // distance between the range's center and the time weighted price of PriceObservations
+        max_deviation_bps: u16,
+    },
+}
+
+impl Strategy {
+    // largest variant
+    pub fn space() -> usize {
+        1 + 32 + 2 + 2 + 2
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Strategy::Manual => true,
+            Strategy::FixedWidth { width_bps } => width_bps > 0,
+            Strategy::Skewed {
+                width_bps,
+                skew_bps,
+            } => width_bps > 0 && (-10000..=10000).contains(&skew_bps),
+            Strategy::Trailing { width_bps, .. } => width_bps > 0,
+            Strategy::TimeDecay {
+                start_width_bps,
+                end_width_bps,
+                decay_seconds,
+            } => end_width_bps > 0 && start_width_bps >= end_width_bps && decay_seconds > 0,
+            Strategy::Volatility {
+                multiplier_bps,
+                min_width_bps,
+                max_width_bps,
+            } => multiplier_bps > 0 && min_width_bps > 0 && min_width_bps <= max_width_bps,
+            Strategy::External {
+                program,
+                min_width_bps,
+                max_width_bps,
+                ..
+            } => {
+                program != Pubkey::default() && min_width_bps > 0 && min_width_bps <= max_width_bps
+            }
+        }
+    }
+
+    // whether a crank call should move the position
+    pub fn should_rebalance(
+        &self,
+        pool: &Whirlpool,
+        position_tick_lower_index: i32,
+        position_tick_upper_index: i32,
+    ) -> bool {
+        let tick_index_current = pool.tick_current_index;
+        if position_in_range(
+            tick_index_current,
+            position_tick_lower_index,
+            position_tick_upper_index,
+        ) {
+            return false;
+        }
+
+        match *self {
+            Strategy::Trailing { trail_up, .. } => {
+                if trail_up {
+                    tick_index_current >= position_tick_upper_index
+                } else {
+                    tick_index_current < position_tick_lower_index
+                }
+            }
+            _ => true,
+        }
+    }
+
+    // range of the next position, None when the crank, price observations or an external
+    // program supply it
+    pub fn range_spec(&self, now: i64, last_rebalance_timestamp: i64) -> Option<RangeSpec> {
+        match *self {
+            Strategy::Manual | Strategy::Volatility { .. } | Strategy::External { .. } => None,
+            Strategy::FixedWidth { width_bps } | Strategy::Trailing { width_bps, .. } => {
+                Some(RangeSpec::WidthAndSkew {
+                    width_bps,
+                    skew_bps: 0,
+                })
+            }
+            Strategy::Skewed {
+                width_bps,
+                skew_bps,
+            } => Some(RangeSpec::WidthAndSkew {
+                width_bps,
+                skew_bps,
+            }),
+            Strategy::TimeDecay {
+                start_width_bps,
+                end_width_bps,
+                decay_seconds,
+            } => {
+                let elapsed = now
+                    .saturating_sub(last_rebalance_timestamp)
+                    .clamp(0, decay_seconds as i64);
+                let decay =
+                    (start_width_bps - end_width_bps) as i64 * elapsed / decay_seconds as i64;
+
+                Some(RangeSpec::WidthAndSkew {
+                    width_bps: start_width_bps - decay as u16,
+                    skew_bps: 0,
+                })
+            }
+        }
+    }
+
+    // aligned (lower_tick_index, upper_tick_index) for the next position
+    pub fn target_tick_range(
+        &self,
+        pool: &Whirlpool,
+        now: i64,
+        last_rebalance_timestamp: i64,
+    ) -> Option<Result<(i32, i32)>> {
+        self.range_spec(now, last_rebalance_timestamp)
+            .map(|range_spec| range_spec.to_tick_range(pool.sqrt_price, pool.tick_spacing))
+    }
+
+    // centered range for Strategy::Volatility, see math::calculate_realized_volatility_ticks
+    pub fn volatility_range_spec(
+        &self,
+        tick_index_current: i32,
+        realized_volatility_ticks: u64,
+    ) -> Option<RangeSpec> {
+        let (multiplier_bps, min_width_bps, max_width_bps) = match *self {
+            Strategy::Volatility {
+                multiplier_bps,
+                min_width_bps,
+                max_width_bps,
+            } => (multiplier_bps, min_width_bps, max_width_bps),
+            _ => return None,
+        };
+
+        let width = (realized_volatility_ticks as u128 * multiplier_bps as u128 / 10000)
+            .min(i32::MAX as u128) as i32;
+        let width = width.clamp(
+            math::bps_to_tick_delta(min_width_bps),
+            math::bps_to_tick_delta(max_width_bps),
+        );
+
+        Some(RangeSpec::Ticks {
+            lower_tick_index: tick_index_current.saturating_sub(width / 2),
+            upper_tick_index: tick_index_current.saturating_add(width - width / 2),
+        })
+    }
+
+    // an external program can ship any range, keep it usable and close to reference_tick_index.
+    // the whirlpool oracle account is a placeholder, so the reference is the time weighted tick
+    // of PriceObservations rather than the pool price a single transaction can move
+    pub fn check_external_tick_range(
+        &self,
+        tick_spacing: u16,
+        reference_tick_index: i32,
+        lower_tick_index: i32,
+        upper_tick_index: i32,
+    ) -> Result<()> {
+        let (min_width_bps, max_width_bps, max_deviation_bps) = match *self {
+            Strategy::External {
+                min_width_bps,
+                max_width_bps,
+                max_deviation_bps,
+                ..
+            } => (min_width_bps, max_width_bps, max_deviation_bps),
+            _ => return Err(error!(BloomErrorCode::InvalidStrategyProgram)),
+        };
+
+        if math::snap_tick_range(lower_tick_index, upper_tick_index, tick_spacing)
+            != Some((lower_tick_index, upper_tick_index))
+        {
+            return Err(error!(BloomErrorCode::StrategyRangeNotAligned));
+        }
+
+        let width = upper_tick_index - lower_tick_index;
+        if width < math::bps_to_tick_delta(min_width_bps) {
+            return Err(error!(BloomErrorCode::StrategyRangeTooNarrow));
+        }
+        if width > math::bps_to_tick_delta(max_width_bps) {
+            return Err(error!(BloomErrorCode::StrategyRangeTooWide));
+        }
+
+        let center = lower_tick_index + width / 2;
+        if (center - reference_tick_index).abs() > math::bps_to_tick_delta(max_deviation_bps) {
+            return Err(error!(BloomErrorCode::StrategyRangeDeviationExceeded));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math;
+
+    fn pool(tick_current_index: i32, tick_spacing: u16) -> Whirlpool {
+        Whirlpool {
+            tick_current_index,
+            sqrt_price: math::tick_index_to_sqrt_price_x64(tick_current_index),
+            tick_spacing,
+            ..Default::default()
+        }
+    }
+
+    fn target(strategy: Strategy, pool: &Whirlpool, now: i64) -> (i32, i32) {
+        strategy.target_tick_range(pool, now, 0).unwrap().unwrap()
+    }
+
+    #[test]
+    fn manual_leaves_range_to_crank() {
+        let strategy = Strategy::Manual;
+        assert!(strategy.target_tick_range(&pool(0, 64), 0, 0).is_none());
+        assert!(strategy.should_rebalance(&pool(1000, 64), -128, 128));
+        assert!(!strategy.should_rebalance(&pool(0, 64), -128, 128));
+    }
+
+    #[test]
+    fn fixed_width_centers_on_price() {
+        let strategy = Strategy::FixedWidth { width_bps: 1000 };
+        // +-5% is -513 and 487 ticks
+        assert_eq!((-512, 512), target(strategy, &pool(0, 64), 0));
+        assert_eq!((-513, 487), target(strategy, &pool(0, 1), 0));
+        assert_eq!((-12544, -11520), target(strategy, &pool(-12000, 64), 0));
+    }
+
+    #[test]
+    fn fixed_width_rebalances_either_side() {
+        let strategy = Strategy::FixedWidth { width_bps: 1000 };
+        assert!(strategy.should_rebalance(&pool(-129, 64), -128, 128));
+        assert!(strategy.should_rebalance(&pool(128, 64), -128, 128));
+        assert!(!strategy.should_rebalance(&pool(127, 64), -128, 128));
+    }
+
+    #[test]
+    fn skewed_shifts_range() {
+        let strategy = Strategy::Skewed {
+            width_bps: 1000,
+            skew_bps: 5000,
+        };
+        // 2.5% below and 7.5% above
+        let (lower, upper) = target(strategy, &pool(0, 1), 0);
+        assert_eq!((-254, 723), (lower, upper));
+
+        let strategy = Strategy::Skewed {
+            width_bps: 1000,
+            skew_bps: -10000,
+        };
+        let (lower, upper) = target(strategy, &pool(0, 1), 0);
+        assert_eq!((-1054, 0), (lower, upper));
+    }
+
+    #[test]
+    fn trailing_up_only_follows_rising_price() {
+        let strategy = Strategy::Trailing {
+            width_bps: 1000,
+            trail_up: true,
+        };
+        assert!(strategy.should_rebalance(&pool(128, 64), -128, 128));
+        assert!(!strategy.should_rebalance(&pool(-129, 64), -128, 128));
+        assert!(!strategy.should_rebalance(&pool(0, 64), -128, 128));
+        assert_eq!((-64, 960), target(strategy, &pool(448, 64), 0));
+    }
+
+    #[test]
+    fn trailing_down_only_follows_falling_price() {
+        let strategy = Strategy::Trailing {
+            width_bps: 1000,
+            trail_up: false,
+        };
+        assert!(strategy.should_rebalance(&pool(-129, 64), -128, 128));
+        assert!(!strategy.should_rebalance(&pool(128, 64), -128, 128));
+    }
+
+    #[test]
+    fn time_decay_narrows_width() {
+        let strategy = Strategy::TimeDecay {
+            start_width_bps: 2000,
+            end_width_bps: 1000,
+            decay_seconds: 100,
+        };
+        assert_eq!(
+            Some(RangeSpec::WidthAndSkew {
+                width_bps: 2000,
+                skew_bps: 0
+            }),
+            strategy.range_spec(0, 0)
+        );
+        assert_eq!(
+            Some(RangeSpec::WidthAndSkew {
+                width_bps: 1500,
+                skew_bps: 0
+            }),
+            strategy.range_spec(50, 0)
+        );
+        // stays at the end width, and clock skew before the last rebalance counts as 0
+        assert_eq!(
+            Some(RangeSpec::WidthAndSkew {
+                width_bps: 1000,
+                skew_bps: 0
+            }),
+            strategy.range_spec(1000, 0)
+        );
+        assert_eq!(
+            Some(RangeSpec::WidthAndSkew {
+                width_bps: 2000,
+                skew_bps: 0
+            }),
+            strategy.range_spec(-10, 0)
+        );
+
+        assert_eq!((-512, 512), target(strategy, &pool(0, 64), 100));
+    }
+
+    fn volatility() -> Strategy {
+        Strategy::Volatility {
+            multiplier_bps: 20000,
+            min_width_bps: 100,
+            max_width_bps: 2000,
+        }
+    }
+
+    #[test]
+    fn volatility_width_follows_realized_volatility() {
+        // 2x 300 ticks
+        assert_eq!(
+            Some(RangeSpec::Ticks {
+                lower_tick_index: -12300,
+                upper_tick_index: -11700,
+            }),
+            volatility().volatility_range_spec(-12000, 300)
+        );
+        let pool = pool(-12000, 64);
+        let (lower, upper) = volatility()
+            .volatility_range_spec(-12000, 300)
+            .unwrap()
+            .to_tick_range(pool.sqrt_price, pool.tick_spacing)
+            .unwrap();
+        assert_eq!((-12288, -11712), (lower, upper));
+    }
+
+    #[test]
+    fn volatility_width_clamped() {
+        // calm markets use min_width_bps, 99 ticks
+        assert_eq!(
+            Some(RangeSpec::Ticks {
+                lower_tick_index: -49,
+                upper_tick_index: 50,
+            }),
+            volatility().volatility_range_spec(0, 0)
+        );
+        // volatile markets use max_width_bps, 1823 ticks
+        assert_eq!(
+            Some(RangeSpec::Ticks {
+                lower_tick_index: -911,
+                upper_tick_index: 912,
+            }),
+            volatility().volatility_range_spec(0, u64::MAX)
+        );
+    }
+
+    #[test]
+    fn volatility_only_for_volatility_strategy() {
+        assert!(volatility().range_spec(0, 0).is_none());
+        assert!(Strategy::FixedWidth { width_bps: 1000 }
+            .volatility_range_spec(0, 300)
+            .is_none());
+        assert!(volatility().should_rebalance(&pool(-129, 64), -128, 128));
+    }
+
+    fn external() -> Strategy {
+        Strategy::External {
+            program: Pubkey::new_from_array([1; 32]),
+            min_width_bps: 100,
+            max_width_bps: 2000,
+            max_deviation_bps: 500,
+        }
+    }
+
+    #[test]
+    fn external_leaves_range_to_program() {
+        assert!(external().range_spec(0, 0).is_none());
+        assert!(external().should_rebalance(&pool(1000, 64), -128, 128));
+    }
+
+    #[test]
+    fn external_range_within_bounds() {
+        assert!(external()
+            .check_external_tick_range(64, 0, -512, 512)
+            .is_ok());
+        // min width is 99 ticks and max width 1823
+        assert!(external().check_external_tick_range(1, 0, -50, 50).is_ok());
+        assert!(external()
+            .check_external_tick_range(64, -12000, -12800, -11200)
+            .is_ok());
+    }
+
+    #[test]
+    fn external_range_not_aligned() {
+        assert_eq!(
+            Err(BloomErrorCode::StrategyRangeNotAligned.into()),
+            external().check_external_tick_range(64, 0, -500, 512)
+        );
+        assert_eq!(
+            Err(BloomErrorCode::StrategyRangeNotAligned.into()),
+            external().check_external_tick_range(64, 0, 512, -512)
+        );
+    }
+
+    #[test]
+    fn external_range_width_bounds() {
+        assert_eq!(
+            Err(BloomErrorCode::StrategyRangeTooNarrow.into()),
+            external().check_external_tick_range(64, 0, 0, 64)
+        );
+        assert_eq!(
+            Err(BloomErrorCode::StrategyRangeTooWide.into()),
+            external().check_external_tick_range(64, 0, -1024, 1024)
+        );
+    }
+
+    #[test]
+    fn external_range_deviation() {
+        // center is 640 ticks above the price, more than 5%
+        assert_eq!(
+            Err(BloomErrorCode::StrategyRangeDeviationExceeded.into()),
+            external().check_external_tick_range(64, 0, 128, 1152)
+        );
+        assert_eq!(
+            Err(BloomErrorCode::StrategyRangeDeviationExceeded.into()),
+            external().check_external_tick_range(64, -1000, 128, 1152)
+        );
+    }
+
+    #[test]
+    fn validates_parameters() {
+        assert!(Strategy::Manual.is_valid());
+        assert!(!Strategy::FixedWidth { width_bps: 0 }.is_valid());
+        assert!(!Strategy::Skewed {
+            width_bps: 1000,
+            skew_bps: 10001
+        }
+        .is_valid());
+        assert!(!Strategy::TimeDecay {
+            start_width_bps: 1000,
+            end_width_bps: 2000,
+            decay_seconds: 100
+        }
+        .is_valid());
+        assert!(!Strategy::TimeDecay {
+            start_width_bps: 2000,
+            end_width_bps: 1000,
+            decay_seconds: 0
+        }
+        .is_valid());
+        assert!(external().is_valid());
+        assert!(!Strategy::External {
+            program: Pubkey::default(),
+            min_width_bps: 100,
+            max_width_bps: 2000,
+            max_deviation_bps: 500,
+        }
+        .is_valid());
+        assert!(!Strategy::External {
+            program: Pubkey::new_from_array([1; 32]),
+            min_width_bps: 3000,
+            max_width_bps: 2000,
+            max_deviation_bps: 500,
+        }
+        .is_valid());
+    }
+}
@@ -48,20 +48,114 @@ export class Client {
       tokenBPoolVault: vaultManagerData.tokenBPoolVault,
       poolPosition: vaultManagerData.poolPosition,
       poolPositionMint: vaultManagerData.poolPositionMint,
-      poolPositionMintSeed: vaultManagerData.poolPositionMintSeed,
+      positionNonce: vaultManagerData.positionNonce,
       poolPositionTokenAccount: vaultManagerData.poolPositionTokenAccount,
       admin: vaultManagerData.admin,
+      strategy: vaultManagerData.strategy,
+      mode: vaultManagerData.mode,
+      rebalanceState: vaultManagerData.rebalanceState,
+      rebalanceLowerTickIndex: vaultManagerData.rebalanceLowerTickIndex,
+      rebalanceUpperTickIndex: vaultManagerData.rebalanceUpperTickIndex,
     };
   }
 
+  public async findVaultStatsAddress(
+    vaultManagerAddress: anchor.web3.PublicKey
+  ): Promise<anchor.web3.PublicKey> {
+    const [vaultStats, _vaultStatsBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("vault_stats"), vaultManagerAddress.toBuffer()],
+        this.program.programId
+      );
+
+    return vaultStats;
+  }
+
+  public async findRebalanceHistoryAddress(
+    vaultManagerAddress: anchor.web3.PublicKey
+  ): Promise<anchor.web3.PublicKey> {
+    const [rebalanceHistory, _rebalanceHistoryBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("rebalance_history"), vaultManagerAddress.toBuffer()],
+        this.program.programId
+      );
+
+    return rebalanceHistory;
+  }
+
+  public async findPriceObservationsAddress(
+    vaultManagerAddress: anchor.web3.PublicKey
+  ): Promise<anchor.web3.PublicKey> {
+    const [priceObservations, _priceObservationsBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("price_observations"), vaultManagerAddress.toBuffer()],
+        this.program.programId
+      );
+
+    return priceObservations;
+  }
+
+  public async findPoolPositionMintAddress(
+    poolAddress: anchor.web3.PublicKey,
+    positionNonce: anchor.BN
+  ): Promise<anchor.web3.PublicKey> {
+    const [poolPositionMint, _poolPositionMintBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("pool_position_mint"),
+          positionNonce.toArrayLike(Buffer, "le", 8),
+          poolAddress.toBuffer(),
+        ],
+        this.program.programId
+      );
+
+    return poolPositionMint;
+  }
+
+  public async findUserPositionAddress(
+    vaultManagerAddress: anchor.web3.PublicKey,
+    user: anchor.web3.PublicKey
+  ): Promise<anchor.web3.PublicKey> {
+    const [userPosition, _userPositionBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("user_position"),
+          vaultManagerAddress.toBuffer(),
+          user.toBuffer(),
+        ],
+        this.program.programId
+      );
+
+    return userPosition;
+  }
+
+  public async findAllowlistEntryAddress(
+    vaultManagerAddress: anchor.web3.PublicKey,
+    user: anchor.web3.PublicKey
+  ): Promise<anchor.web3.PublicKey> {
+    const [allowlistEntry, _allowlistEntryBump] =
+      await anchor.web3.PublicKey.findProgramAddress(
+        [
+          Buffer.from("allowlist"),
+          vaultManagerAddress.toBuffer(),
+          user.toBuffer(),
+        ],
+        this.program.programId
+      );
+
+    return allowlistEntry;
+  }
+
   public async fetchPool(
     pool: anchor.web3.PublicKey
   ): Promise<whirlpool.WhirlpoolData> {
     return await this.wpClient.getFetcher().getPool(pool, true);
   }
 
+  // rangeOrder places a one sided order next to the price instead of around it
   public async initializeVault(
-    poolAddress: anchor.web3.PublicKey
+    poolAddress: anchor.web3.PublicKey,
+    rangeOrder?: { sellA: boolean }
   ): Promise<anchor.web3.PublicKey> {
     const pool = await this.fetchPool(poolAddress);
 
@@ -148,16 +242,10 @@ export class Client {
     );
 
     // pool position PDAs
-    const poolPositionMintSeed = "dkfjgl";
-    const [poolPositionMint, _poolPositionMintBump] =
-      await anchor.web3.PublicKey.findProgramAddress(
-        [
-          Buffer.from("pool_position_mint"),
-          Buffer.from(poolPositionMintSeed),
-          poolAddress.toBuffer(),
-        ],
-        this.program.programId
-      );
+    const poolPositionMint = await this.findPoolPositionMintAddress(
+      poolAddress,
+      new anchor.BN(0)
+    );
     const poolPositionPda = whirlpool.PDAUtil.getPosition(
       whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
       poolPositionMint
@@ -168,11 +256,41 @@ export class Client {
       true
     );
 
+    // sell orders start a tick spacing above the price, buy orders end below it
+    const tickIndexFloor =
+      pool.tickCurrentIndex -
+      (((pool.tickCurrentIndex % pool.tickSpacing) + pool.tickSpacing) %
+        pool.tickSpacing);
+
+    const initializeVaultMethod =
+      rangeOrder === undefined
+        ? this.program.methods.initializeVault(lowerTickIndex, upperTickIndex)
+        : this.program.methods.initializeRangeOrderVault(
+            rangeOrder.sellA
+              ? {
+                  ticks: {
+                    lowerTickIndex: tickIndexFloor + pool.tickSpacing,
+                    upperTickIndex: upperTickIndex,
+                  },
+                }
+              : {
+                  ticks: {
+                    lowerTickIndex: lowerTickIndex,
+                    upperTickIndex: tickIndexFloor,
+                  },
+                },
+            rangeOrder.sellA
+          );
+
     // initialize new vault
-    const initializeVaultTxSig = await this.program.methods
-      .initializeVault(poolPositionMintSeed, lowerTickIndex, upperTickIndex)
+    const initializeVaultTxSig = await initializeVaultMethod
       .accounts({
         vaultManager: vaultManager,
+        vaultStats: await this.findVaultStatsAddress(vaultManager),
+        rebalanceHistory: await this.findRebalanceHistoryAddress(vaultManager),
+        priceObservations: await this.findPriceObservationsAddress(
+          vaultManager
+        ),
         tokenA: pool.tokenMintA,
         tokenB: pool.tokenMintB,
         poolToken: poolToken,
@@ -196,19 +314,33 @@ export class Client {
     return vaultManager;
   }
 
+  // resize a vault created with an older VaultManager layout
+  public async migrateVault(vaultManagerAddress: anchor.web3.PublicKey) {
+    const migrateVaultTxSig = await this.program.methods
+      .migrateVault()
+      .accounts({
+        vaultManager: vaultManagerAddress,
+        vaultStats: await this.findVaultStatsAddress(vaultManagerAddress),
+        rebalanceHistory: await this.findRebalanceHistoryAddress(
+          vaultManagerAddress
+        ),
+        priceObservations: await this.findPriceObservationsAddress(
+          vaultManagerAddress
+        ),
+        admin: this.provider.wallet.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc({ skipPreflight: true });
+    console.log("migrateVaultTxSig: %s", migrateVaultTxSig);
+  }
+
   public async rebalancePositions(vaultManagerAddress: anchor.web3.PublicKey) {
     const vaultManagerData = await this.fetchVaultManager(vaultManagerAddress);
 
-    const newPoolPositionMintSeed = "lakdls";
-    const [newPoolPositionMint, _poolPositionMintBump] =
-      await anchor.web3.PublicKey.findProgramAddress(
-        [
-          Buffer.from("pool_position_mint"),
-          Buffer.from(newPoolPositionMintSeed),
-          vaultManagerData.pool.toBuffer(),
-        ],
-        this.program.programId
-      );
+    const newPoolPositionMint = await this.findPoolPositionMintAddress(
+      vaultManagerData.pool,
+      vaultManagerData.positionNonce.addn(1)
+    );
     const newPoolPositionPda = whirlpool.PDAUtil.getPosition(
       whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
       newPoolPositionMint
@@ -254,11 +386,6 @@ export class Client {
       poolData.tickSpacing
     );
 
-    const currentTickIndex = whirlpool.TickUtil.getInitializableTickIndex(
-      poolData.tickCurrentIndex,
-      poolData.tickSpacing
-    );
-
     const upperTickIndex = whirlpool.PriceMath.priceToInitializableTickIndex(
       upperPrice,
       tokenADecimals,
@@ -266,17 +393,35 @@ export class Client {
       poolData.tickSpacing
     );
 
-    const lowerTickIndexPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
-      lowerTickIndex,
-      poolData.tickSpacing,
-      1,
-      whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
-      vaultManagerData.pool,
+    const oldPosition = await this.wpClient.getPosition(
+      vaultManagerData.poolPosition,
       true
     );
 
-    const currentTickIndexPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
-      currentTickIndex,
+    // a filled range order is placed the other way with the same width
+    let newLowerTickIndex = lowerTickIndex;
+    let newUpperTickIndex = upperTickIndex;
+    if ("rangeOrder" in vaultManagerData.mode) {
+      const width =
+        oldPosition.getData().tickUpperIndex -
+        oldPosition.getData().tickLowerIndex;
+      const tickIndexFloor =
+        poolData.tickCurrentIndex -
+        (((poolData.tickCurrentIndex % poolData.tickSpacing) +
+          poolData.tickSpacing) %
+          poolData.tickSpacing);
+
+      if (vaultManagerData.mode.rangeOrder.sellA) {
+        newLowerTickIndex = tickIndexFloor - width;
+        newUpperTickIndex = tickIndexFloor;
+      } else {
+        newLowerTickIndex = tickIndexFloor + poolData.tickSpacing;
+        newUpperTickIndex = newLowerTickIndex + width;
+      }
+    }
+
+    const lowerTickIndexPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
+      newLowerTickIndex,
       poolData.tickSpacing,
       1,
       whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
@@ -285,7 +430,7 @@ export class Client {
     );
 
     const upperTickIndexPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
-      upperTickIndex,
+      newUpperTickIndex,
       poolData.tickSpacing,
       1,
       whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
@@ -294,11 +439,6 @@ export class Client {
     );
 
     // get old ticks for decreasing liquidity
-    const oldPosition = await this.wpClient.getPosition(
-      vaultManagerData.poolPosition,
-      true
-    );
-
     const oldLowerTickIndexPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
       oldPosition.getData().tickLowerIndex,
       poolData.tickSpacing,
@@ -322,6 +462,44 @@ export class Client {
       vaultManagerData.pool
     );
 
+    // the vault swaps what the old position and the vault token accounts hold into the new range
+    const oldPositionAmounts = whirlpool.PoolUtil.getTokenAmountsFromLiquidity(
+      oldPosition.getData().liquidity,
+      poolData.sqrtPrice,
+      whirlpool.PriceMath.tickIndexToSqrtPriceX64(
+        oldPosition.getData().tickLowerIndex
+      ),
+      whirlpool.PriceMath.tickIndexToSqrtPriceX64(
+        oldPosition.getData().tickUpperIndex
+      ),
+      false
+    );
+    const [tokenAVaultAmount, tokenBVaultAmount] =
+      await this.fetchVaultAmounts(vaultManagerData);
+    const swapTickArrays = await this.getSwapTickArrays(
+      vaultManagerData.pool,
+      poolData,
+      this.rebalanceSwapAToB(
+        poolData.sqrtPrice,
+        tokenAVaultAmount.add(oldPositionAmounts.tokenA),
+        tokenBVaultAmount.add(oldPositionAmounts.tokenB),
+        newLowerTickIndex,
+        newUpperTickIndex
+      )
+    );
+
+    // external strategies are invoked with the program as the first remaining account
+    const strategyAccounts =
+      "external" in vaultManagerData.strategy
+        ? [
+            {
+              pubkey: vaultManagerData.strategy.external.program,
+              isSigner: false,
+              isWritable: false,
+            },
+          ]
+        : [];
+
     const increaseComputeBudgetIx =
       anchor.web3.ComputeBudgetProgram.requestUnits({
         units: 600_000,
@@ -329,15 +507,19 @@ export class Client {
       });
 
     const rebalanceOpenPositionTxSig = await this.program.methods
-      .rebalancePositions(
-        newPoolPositionMintSeed,
-        lowerTickIndex,
-        upperTickIndex
-      )
+      .rebalancePositions(lowerTickIndex, upperTickIndex)
       .accounts({
         vaultManager: vaultManagerAddress,
+        vaultStats: await this.findVaultStatsAddress(vaultManagerAddress),
+        rebalanceHistory: await this.findRebalanceHistoryAddress(
+          vaultManagerAddress
+        ),
+        priceObservations: await this.findPriceObservationsAddress(
+          vaultManagerAddress
+        ),
         tokenA: vaultManagerData.tokenA,
         tokenB: vaultManagerData.tokenB,
+        poolToken: vaultManagerData.poolToken,
         tokenAVault: vaultManagerData.tokenAVault,
         tokenBVault: vaultManagerData.tokenBVault,
         pool: vaultManagerData.pool,
@@ -349,7 +531,9 @@ export class Client {
         oldTickArrayLower: oldLowerTickIndexPda[0].publicKey,
         oldTickArrayUpper: oldUpperTickIndexPda[0].publicKey,
         newTickArrayLower: lowerTickIndexPda[0].publicKey,
-        tickArrayCurrent: currentTickIndexPda[0].publicKey,
+        tickArrayCurrent: swapTickArrays[0],
+        tickArray1: swapTickArrays[1],
+        tickArray2: swapTickArrays[2],
         newTickArrayUpper: upperTickIndexPda[0].publicKey,
         oracle: oraclePda.publicKey,
         newPoolPosition: newPoolPositionPda.publicKey,
@@ -362,26 +546,369 @@ export class Client {
         rent: anchor.web3.SYSVAR_RENT_PUBKEY,
         associatedTokenProgram: splToken.ASSOCIATED_TOKEN_PROGRAM_ID,
       })
+      .remainingAccounts(strategyAccounts)
       .preInstructions([increaseComputeBudgetIx])
       .rpc({ skipPreflight: true });
     console.log("rebalanceOpenPositionTxSig: %s", rebalanceOpenPositionTxSig);
   }
 
+  // first step of a split rebalance, withdraws the position into the vault token accounts
+  public async rebalanceWithdraw(vaultManagerAddress: anchor.web3.PublicKey) {
+    const vaultManagerData = await this.fetchVaultManager(vaultManagerAddress);
+    const poolData = await this.fetchPool(vaultManagerData.pool);
+    const position = (
+      await this.wpClient.getPosition(vaultManagerData.poolPosition, true)
+    ).getData();
+
+    const [lowerTickIndex, upperTickIndex] =
+      await this.getTargetTickRange(poolData);
+
+    const tickArrayLowerPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
+      position.tickLowerIndex,
+      poolData.tickSpacing,
+      1,
+      whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      vaultManagerData.pool,
+      true
+    );
+    const tickArrayUpperPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
+      position.tickUpperIndex,
+      poolData.tickSpacing,
+      1,
+      whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      vaultManagerData.pool,
+      true
+    );
+
+    const rebalanceWithdrawTxSig = await this.program.methods
+      .rebalanceWithdraw(lowerTickIndex, upperTickIndex)
+      .accounts({
+        vaultManager: vaultManagerAddress,
+        vaultStats: await this.findVaultStatsAddress(vaultManagerAddress),
+        rebalanceHistory: await this.findRebalanceHistoryAddress(
+          vaultManagerAddress
+        ),
+        priceObservations: await this.findPriceObservationsAddress(
+          vaultManagerAddress
+        ),
+        tokenA: vaultManagerData.tokenA,
+        tokenB: vaultManagerData.tokenB,
+        poolToken: vaultManagerData.poolToken,
+        tokenAVault: vaultManagerData.tokenAVault,
+        tokenBVault: vaultManagerData.tokenBVault,
+        pool: vaultManagerData.pool,
+        tokenAPoolVault: poolData.tokenVaultA,
+        tokenBPoolVault: poolData.tokenVaultB,
+        poolPosition: vaultManagerData.poolPosition,
+        poolPositionMint: vaultManagerData.poolPositionMint,
+        poolPositionTokenAccount: vaultManagerData.poolPositionTokenAccount,
+        tickArrayLower: tickArrayLowerPda[0].publicKey,
+        tickArrayUpper: tickArrayUpperPda[0].publicKey,
+        crank: this.provider.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        whirlpoolProgram: whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      })
+      .rpc({ skipPreflight: true });
+    console.log("rebalanceWithdrawTxSig: %s", rebalanceWithdrawTxSig);
+  }
+
+  // second step of a split rebalance, swaps the vault balances into the target range's ratio
+  public async rebalanceSwap(vaultManagerAddress: anchor.web3.PublicKey) {
+    const vaultManagerData = await this.fetchVaultManager(vaultManagerAddress);
+    const poolData = await this.fetchPool(vaultManagerData.pool);
+
+    const [tokenAVaultAmount, tokenBVaultAmount] =
+      await this.fetchVaultAmounts(vaultManagerData);
+    const swapTickArrays = await this.getSwapTickArrays(
+      vaultManagerData.pool,
+      poolData,
+      this.rebalanceSwapAToB(
+        poolData.sqrtPrice,
+        tokenAVaultAmount,
+        tokenBVaultAmount,
+        vaultManagerData.rebalanceLowerTickIndex,
+        vaultManagerData.rebalanceUpperTickIndex
+      )
+    );
+
+    const oraclePda = whirlpool.PDAUtil.getOracle(
+      whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      vaultManagerData.pool
+    );
+
+    const rebalanceSwapTxSig = await this.program.methods
+      .rebalanceSwap()
+      .accounts({
+        vaultManager: vaultManagerAddress,
+        vaultStats: await this.findVaultStatsAddress(vaultManagerAddress),
+        rebalanceHistory: await this.findRebalanceHistoryAddress(
+          vaultManagerAddress
+        ),
+        priceObservations: await this.findPriceObservationsAddress(
+          vaultManagerAddress
+        ),
+        tokenA: vaultManagerData.tokenA,
+        tokenB: vaultManagerData.tokenB,
+        tokenAVault: vaultManagerData.tokenAVault,
+        tokenBVault: vaultManagerData.tokenBVault,
+        pool: vaultManagerData.pool,
+        tokenAPoolVault: poolData.tokenVaultA,
+        tokenBPoolVault: poolData.tokenVaultB,
+        tickArrayCurrent: swapTickArrays[0],
+        tickArray1: swapTickArrays[1],
+        tickArray2: swapTickArrays[2],
+        oracle: oraclePda.publicKey,
+        crank: this.provider.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        whirlpoolProgram: whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      })
+      .rpc({ skipPreflight: true });
+    console.log("rebalanceSwapTxSig: %s", rebalanceSwapTxSig);
+  }
+
+  // last step of a split rebalance, opens the target range and deposits the vault balances
+  public async rebalanceDeposit(vaultManagerAddress: anchor.web3.PublicKey) {
+    const vaultManagerData = await this.fetchVaultManager(vaultManagerAddress);
+    const poolData = await this.fetchPool(vaultManagerData.pool);
+
+    const newPoolPositionMint = await this.findPoolPositionMintAddress(
+      vaultManagerData.pool,
+      vaultManagerData.positionNonce.addn(1)
+    );
+    const newPoolPositionPda = whirlpool.PDAUtil.getPosition(
+      whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      newPoolPositionMint
+    );
+    const newPoolPositionTokenAccount =
+      await splToken.getAssociatedTokenAddress(
+        newPoolPositionMint,
+        vaultManagerAddress,
+        true
+      );
+
+    const newLowerTickIndexPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
+      vaultManagerData.rebalanceLowerTickIndex,
+      poolData.tickSpacing,
+      1,
+      whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      vaultManagerData.pool,
+      true
+    );
+    const newUpperTickIndexPda = await whirlpool.TickArrayUtil.getTickArrayPDAs(
+      vaultManagerData.rebalanceUpperTickIndex,
+      poolData.tickSpacing,
+      1,
+      whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      vaultManagerData.pool,
+      true
+    );
+
+    const rebalanceDepositTxSig = await this.program.methods
+      .rebalanceDeposit()
+      .accounts({
+        vaultManager: vaultManagerAddress,
+        vaultStats: await this.findVaultStatsAddress(vaultManagerAddress),
+        rebalanceHistory: await this.findRebalanceHistoryAddress(
+          vaultManagerAddress
+        ),
+        tokenA: vaultManagerData.tokenA,
+        tokenB: vaultManagerData.tokenB,
+        poolToken: vaultManagerData.poolToken,
+        tokenAVault: vaultManagerData.tokenAVault,
+        tokenBVault: vaultManagerData.tokenBVault,
+        pool: vaultManagerData.pool,
+        tokenAPoolVault: poolData.tokenVaultA,
+        tokenBPoolVault: poolData.tokenVaultB,
+        newTickArrayLower: newLowerTickIndexPda[0].publicKey,
+        newTickArrayUpper: newUpperTickIndexPda[0].publicKey,
+        newPoolPosition: newPoolPositionPda.publicKey,
+        newPoolPositionMint: newPoolPositionMint,
+        newPoolPositionTokenAccount: newPoolPositionTokenAccount,
+        crank: this.provider.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        whirlpoolProgram: whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        associatedTokenProgram: splToken.ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .rpc({ skipPreflight: true });
+    console.log("rebalanceDepositTxSig: %s", rebalanceDepositTxSig);
+  }
+
+  // admin only, moves a split rebalance stuck on a stale range to a new one around the price
+  public async retargetRebalance(
+    vaultManagerAddress: anchor.web3.PublicKey,
+    rangeSpec: RangeSpec
+  ) {
+    const vaultManagerData = await this.fetchVaultManager(vaultManagerAddress);
+
+    const retargetRebalanceTxSig = await this.program.methods
+      .retargetRebalance(rangeSpec)
+      .accounts({
+        vaultManager: vaultManagerAddress,
+        pool: vaultManagerData.pool,
+        admin: this.provider.wallet.publicKey,
+      })
+      .rpc({ skipPreflight: true });
+    console.log("retargetRebalanceTxSig: %s", retargetRebalanceTxSig);
+  }
+
+  // +-5% around the current price, the same range rebalancePositions asks for
+  private async getTargetTickRange(
+    poolData: whirlpool.WhirlpoolData
+  ): Promise<[number, number]> {
+    const tokenADecimals = (
+      await splToken.getMint(
+        this.provider.connection,
+        poolData.tokenMintA,
+        "confirmed"
+      )
+    ).decimals;
+    const tokenBDecimals = (
+      await splToken.getMint(
+        this.provider.connection,
+        poolData.tokenMintB,
+        "confirmed"
+      )
+    ).decimals;
+
+    const currentPrice = whirlpool.PriceMath.sqrtPriceX64ToPrice(
+      poolData.sqrtPrice,
+      tokenADecimals,
+      tokenBDecimals
+    );
+
+    return [
+      whirlpool.PriceMath.priceToInitializableTickIndex(
+        currentPrice.mul(new Decimal(0.95)),
+        tokenADecimals,
+        tokenBDecimals,
+        poolData.tickSpacing
+      ),
+      whirlpool.PriceMath.priceToInitializableTickIndex(
+        currentPrice.mul(new Decimal(1.05)),
+        tokenADecimals,
+        tokenBDecimals,
+        poolData.tickSpacing
+      ),
+    ];
+  }
+
+  private async fetchVaultAmounts(
+    vaultManagerData: VaultData
+  ): Promise<[anchor.BN, anchor.BN]> {
+    const tokenAVault = await splToken.getAccount(
+      this.provider.connection,
+      vaultManagerData.tokenAVault,
+      "confirmed"
+    );
+    const tokenBVault = await splToken.getAccount(
+      this.provider.connection,
+      vaultManagerData.tokenBVault,
+      "confirmed"
+    );
+
+    return [
+      new anchor.BN(tokenAVault.amount.toString()),
+      new anchor.BN(tokenBVault.amount.toString()),
+    ];
+  }
+
+  // the tick arrays a swap from the current price crosses, in the order check_swap_tick_arrays
+  // expects. a b to a swap starts from the array holding the next initializable tick
+  private async getSwapTickArrays(
+    poolAddress: anchor.web3.PublicKey,
+    poolData: whirlpool.WhirlpoolData,
+    aToB: boolean
+  ): Promise<anchor.web3.PublicKey[]> {
+    const tickArrayPdas = await whirlpool.TickArrayUtil.getTickArrayPDAs(
+      aToB
+        ? poolData.tickCurrentIndex
+        : poolData.tickCurrentIndex + poolData.tickSpacing,
+      poolData.tickSpacing,
+      3,
+      whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+      poolAddress,
+      aToB
+    );
+
+    return tickArrayPdas.map((tickArrayPda) => tickArrayPda.publicKey);
+  }
+
+  // direction calculate_rebalance_swap picks: sell token a when there is more of it than the
+  // range's deposit ratio asks for
+  private rebalanceSwapAToB(
+    sqrtPrice: anchor.BN,
+    tokenAAmount: anchor.BN,
+    tokenBAmount: anchor.BN,
+    tickLowerIndex: number,
+    tickUpperIndex: number
+  ): boolean {
+    const depositRatio = whirlpool.PoolUtil.getTokenAmountsFromLiquidity(
+      new anchor.BN(1_000_000_000),
+      sqrtPrice,
+      whirlpool.PriceMath.tickIndexToSqrtPriceX64(tickLowerIndex),
+      whirlpool.PriceMath.tickIndexToSqrtPriceX64(tickUpperIndex),
+      false
+    );
+
+    return tokenAAmount
+      .mul(depositRatio.tokenB)
+      .gt(tokenBAmount.mul(depositRatio.tokenA));
+  }
+
+  // pays at most maxSlippageBps more token b than the deposit needs at the current price
   public async addLiquidity(
     vaultManagerAddress: anchor.web3.PublicKey,
-    tokenAAmountIn: anchor.BN
+    tokenAAmountIn: anchor.BN,
+    maxSlippageBps: number = 100
   ) {
     const vaultManagerData = await this.fetchVaultManager(vaultManagerAddress);
+    const poolData = await this.fetchPool(vaultManagerData.pool);
+    const position = (
+      await this.wpClient.getPosition(vaultManagerData.poolPosition, true)
+    ).getData();
 
-    const [poolPositionMint, _poolPositionMintBump] =
-      await anchor.web3.PublicKey.findProgramAddress(
-        [
-          Buffer.from("pool_position_mint"),
-          Buffer.from(vaultManagerData.poolPositionMintSeed),
-          vaultManagerData.pool.toBuffer(),
-        ],
-        this.program.programId
-      );
+    const sqrtPriceLower = whirlpool.PriceMath.tickIndexToSqrtPriceX64(
+      position.tickLowerIndex
+    );
+    const sqrtPriceUpper = whirlpool.PriceMath.tickIndexToSqrtPriceX64(
+      position.tickUpperIndex
+    );
+    // a resting range order below the price is deposited into as if the price was at its lower tick
+    const sqrtPriceCurrent = anchor.BN.max(poolData.sqrtPrice, sqrtPriceLower);
+
+    // same as get_liquidity_from_token_a
+    const liquidity = tokenAAmountIn
+      .mul(sqrtPriceCurrent)
+      .mul(sqrtPriceUpper)
+      .div(sqrtPriceUpper.sub(sqrtPriceCurrent).shln(64));
+    const tokenB = whirlpool.PoolUtil.getTokenAmountsFromLiquidity(
+      liquidity,
+      sqrtPriceCurrent,
+      sqrtPriceLower,
+      sqrtPriceUpper,
+      true
+    ).tokenB;
+
+    await this.addLiquidityWithMode(vaultManagerAddress, {
+      exactA: {
+        tokenAAmountIn: tokenAAmountIn,
+        tokenMaxB: tokenB
+          .muln(10_000 + maxSlippageBps)
+          .divn(10_000)
+          .addn(1),
+      },
+    });
+  }
+
+  public async addLiquidityWithMode(
+    vaultManagerAddress: anchor.web3.PublicKey,
+    depositMode: DepositMode
+  ) {
+    const vaultManagerData = await this.fetchVaultManager(vaultManagerAddress);
+
+    const poolPositionMint = vaultManagerData.poolPositionMint;
     const poolPositionPda = whirlpool.PDAUtil.getPosition(
       whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
       poolPositionMint
@@ -437,9 +964,10 @@ export class Client {
     );
 
     const addLiquidityTxSig = await this.program.methods
-      .addLiquidity(tokenAAmountIn)
+      .addLiquidity(depositMode)
       .accounts({
         vaultManager: vaultManagerAddress,
+        vaultStats: await this.findVaultStatsAddress(vaultManagerAddress),
         tokenA: vaultManagerData.tokenA,
         tokenB: vaultManagerData.tokenB,
         poolToken: vaultManagerData.poolToken,
@@ -455,28 +983,42 @@ export class Client {
         userTokenAAta: userTokenAAta,
         userTokenBAta: userTokenBAta,
         userPoolTokenAta: userPoolTokenAta,
+        userPosition: await this.findUserPositionAddress(
+          vaultManagerAddress,
+          this.provider.wallet.publicKey
+        ),
         tokenProgram: splToken.TOKEN_PROGRAM_ID,
         whirlpoolProgram: whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
         systemProgram: anchor.web3.SystemProgram.programId,
         rent: anchor.web3.SYSVAR_RENT_PUBKEY,
         associatedTokenProgram: splToken.ASSOCIATED_TOKEN_PROGRAM_ID,
       })
+      // only read by vaults that are not open to everyone
+      .remainingAccounts([
+        {
+          pubkey: await this.findAllowlistEntryAddress(
+            vaultManagerAddress,
+            this.provider.wallet.publicKey
+          ),
+          isWritable: false,
+          isSigner: false,
+        },
+      ])
       .rpc({ skipPreflight: false });
     console.log("addLiquidityTxSig: %s", addLiquidityTxSig);
   }
 
   public async removeLiquidity(vaultManagerAddress: anchor.web3.PublicKey) {
+    await this.removeLiquidityWithMode(vaultManagerAddress, { all: {} });
+  }
+
+  public async removeLiquidityWithMode(
+    vaultManagerAddress: anchor.web3.PublicKey,
+    withdrawMode: WithdrawMode
+  ) {
     const vaultManagerData = await this.fetchVaultManager(vaultManagerAddress);
 
-    const [poolPositionMint, _poolPositionMintBump] =
-      await anchor.web3.PublicKey.findProgramAddress(
-        [
-          Buffer.from("pool_position_mint"),
-          Buffer.from(vaultManagerData.poolPositionMintSeed),
-          vaultManagerData.pool.toBuffer(),
-        ],
-        this.program.programId
-      );
+    const poolPositionMint = vaultManagerData.poolPositionMint;
     const poolPositionPda = whirlpool.PDAUtil.getPosition(
       whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
       poolPositionMint
@@ -531,9 +1073,10 @@ export class Client {
     );
 
     const removeLiquidityTxSig = await this.program.methods
-      .removeLiquidity()
+      .removeLiquidity(withdrawMode)
       .accounts({
         vaultManager: vaultManagerAddress,
+        vaultStats: await this.findVaultStatsAddress(vaultManagerAddress),
         tokenA: vaultManagerData.tokenA,
         tokenB: vaultManagerData.tokenB,
         pool: vaultManagerData.pool,
@@ -549,8 +1092,13 @@ export class Client {
         userTokenAAta: userTokenAAta,
         userTokenBAta: userTokenBAta,
         userPoolTokenAta: userPoolTokenAta,
+        userPosition: await this.findUserPositionAddress(
+          vaultManagerAddress,
+          this.provider.wallet.publicKey
+        ),
         tokenProgram: splToken.TOKEN_PROGRAM_ID,
         whirlpoolProgram: whirlpool.ORCA_WHIRLPOOL_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
       })
       .rpc({ skipPreflight: true });
     console.log("removeLiquidityTxSig: %s", removeLiquidityTxSig);
@@ -629,7 +1177,76 @@ export interface VaultData {
   tokenBPoolVault: anchor.web3.PublicKey;
   poolPosition: anchor.web3.PublicKey | null;
   poolPositionMint: anchor.web3.PublicKey | null;
-  poolPositionMintSeed: string | null;
+  positionNonce: anchor.BN;
   poolPositionTokenAccount: anchor.web3.PublicKey | null;
   admin: anchor.web3.PublicKey;
+  strategy: Strategy;
+  mode: VaultMode;
+  rebalanceState: RebalanceState;
+  rebalanceLowerTickIndex: number;
+  rebalanceUpperTickIndex: number;
 }
+
+export type DepositMode =
+  | { exactA: { tokenAAmountIn: anchor.BN; tokenMaxB: anchor.BN } }
+  | { exactB: { tokenBAmountIn: anchor.BN; tokenMaxA: anchor.BN } }
+  | {
+      exactLiquidity: {
+        liquidity: anchor.BN;
+        tokenMaxA: anchor.BN;
+        tokenMaxB: anchor.BN;
+      };
+    }
+  | {
+      exactShares: {
+        poolTokenAmount: anchor.BN;
+        tokenMaxA: anchor.BN;
+        tokenMaxB: anchor.BN;
+      };
+    };
+
+export type WithdrawMode =
+  | { all: {} }
+  | { exactLiquidity: { liquidity: anchor.BN } }
+  | { exactShares: { poolTokenAmount: anchor.BN } };
+
+export type RangeSpec =
+  | { ticks: { lowerTickIndex: number; upperTickIndex: number } }
+  | { bpsOffsets: { lowerBps: number; upperBps: number } }
+  | { widthAndSkew: { widthBps: number; skewBps: number } };
+
+export type VaultMode = { liquidity: {} } | { rangeOrder: { sellA: boolean } };
+
+export type RebalanceState =
+  | { idle: {} }
+  | { withdrawn: {} }
+  | { swapped: {} }
+  | { deposited: {} };
+
+export type Strategy =
+  | { manual: {} }
+  | { fixedWidth: { widthBps: number } }
+  | { skewed: { widthBps: number; skewBps: number } }
+  | { trailing: { widthBps: number; trailUp: boolean } }
+  | {
+      timeDecay: {
+        startWidthBps: number;
+        endWidthBps: number;
+        decaySeconds: number;
+      };
+    }
+  | {
+      volatility: {
+        multiplierBps: number;
+        minWidthBps: number;
+        maxWidthBps: number;
+      };
+    }
+  | {
+      external: {
+        program: anchor.web3.PublicKey;
+        minWidthBps: number;
+        maxWidthBps: number;
+        maxDeviationBps: number;
+      };
+    };
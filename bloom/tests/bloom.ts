@@ -172,8 +172,181 @@ describe("bloom", () => {
 
     await user1BloomClient.rebalancePositions(vaultManagerAddress);
   });
+
+  it("split rebalance locks the vault until the deposit step", async () => {
+    const [user1BloomClient, user1TestClient] = await initUserClients(
+      connection
+    );
+
+    const poolAddress = await user1TestClient.initTestEnvironment([
+      user1BloomClient.provider.wallet.publicKey,
+    ]);
+
+    const vaultManagerAddress = await user1BloomClient.initializeVault(
+      poolAddress
+    );
+
+    await moveOutOfRange(
+      user1BloomClient,
+      user1TestClient,
+      vaultManagerAddress
+    );
+
+    // the first crank only samples the price, no time weighted price yet
+    await user1BloomClient.rebalanceWithdraw(vaultManagerAddress);
+    assert.ok(
+      "idle" in
+        (await user1BloomClient.fetchVaultManager(vaultManagerAddress))
+          .rebalanceState
+    );
+
+    await sleep(2000);
+    await user1BloomClient.rebalanceWithdraw(vaultManagerAddress);
+    assert.ok(
+      "withdrawn" in
+        (await user1BloomClient.fetchVaultManager(vaultManagerAddress))
+          .rebalanceState
+    );
+
+    // deposits and withdrawals wait for the rebalance to finish
+    await assertFails(
+      user1BloomClient.addLiquidity(vaultManagerAddress, new anchor.BN(1000))
+    );
+    await assertFails(user1BloomClient.removeLiquidity(vaultManagerAddress));
+
+    await user1BloomClient.rebalanceSwap(vaultManagerAddress);
+    assert.ok(
+      "swapped" in
+        (await user1BloomClient.fetchVaultManager(vaultManagerAddress))
+          .rebalanceState
+    );
+
+    await user1BloomClient.rebalanceDeposit(vaultManagerAddress);
+    assert.ok(
+      "idle" in
+        (await user1BloomClient.fetchVaultManager(vaultManagerAddress))
+          .rebalanceState
+    );
+    assert.ok(await user1BloomClient.isPositionInRange(vaultManagerAddress));
+
+    await user1BloomClient.removeLiquidity(vaultManagerAddress);
+  });
+
+  it("admin retargets a split rebalance", async () => {
+    const [user1BloomClient, user1TestClient] = await initUserClients(
+      connection
+    );
+    const [user2BloomClient, _user2TestClient] = await initUserClients(
+      connection
+    );
+
+    const poolAddress = await user1TestClient.initTestEnvironment([
+      user1BloomClient.provider.wallet.publicKey,
+      user2BloomClient.provider.wallet.publicKey,
+    ]);
+
+    const vaultManagerAddress = await user1BloomClient.initializeVault(
+      poolAddress
+    );
+
+    await moveOutOfRange(
+      user1BloomClient,
+      user1TestClient,
+      vaultManagerAddress
+    );
+
+    await user1BloomClient.rebalanceWithdraw(vaultManagerAddress);
+    await sleep(2000);
+    await user1BloomClient.rebalanceWithdraw(vaultManagerAddress);
+    await user1BloomClient.rebalanceSwap(vaultManagerAddress);
+
+    const vaultManagerData = await user1BloomClient.fetchVaultManager(
+      vaultManagerAddress
+    );
+    assert.ok("swapped" in vaultManagerData.rebalanceState);
+
+    // a wider range around the same price
+    const width =
+      vaultManagerData.rebalanceUpperTickIndex -
+      vaultManagerData.rebalanceLowerTickIndex;
+    const rangeSpec = {
+      ticks: {
+        lowerTickIndex: vaultManagerData.rebalanceLowerTickIndex - width,
+        upperTickIndex: vaultManagerData.rebalanceUpperTickIndex + width,
+      },
+    };
+
+    // only the vault admin can retarget
+    await assertFails(
+      user2BloomClient.retargetRebalance(vaultManagerAddress, rangeSpec)
+    );
+
+    await user1BloomClient.retargetRebalance(vaultManagerAddress, rangeSpec);
+
+    const retargetedVaultManagerData = await user1BloomClient.fetchVaultManager(
+      vaultManagerAddress
+    );
+    assert.ok("withdrawn" in retargetedVaultManagerData.rebalanceState);
+    assert.equal(
+      rangeSpec.ticks.lowerTickIndex,
+      retargetedVaultManagerData.rebalanceLowerTickIndex
+    );
+    assert.equal(
+      rangeSpec.ticks.upperTickIndex,
+      retargetedVaultManagerData.rebalanceUpperTickIndex
+    );
+
+    // the retargeted range is swapped for and deposited like any other
+    await user1BloomClient.rebalanceSwap(vaultManagerAddress);
+    await user1BloomClient.rebalanceDeposit(vaultManagerAddress);
+    assert.ok(
+      "idle" in
+        (await user1BloomClient.fetchVaultManager(vaultManagerAddress))
+          .rebalanceState
+    );
+  });
 });
 
+// deposits into the vault and swaps the pool price below the vault's position
+async function moveOutOfRange(
+  bloomClient: sdk.Client,
+  testClient: testSdk.Client,
+  vaultManagerAddress: anchor.web3.PublicKey
+) {
+  const vaultManager = await bloomClient.fetchVaultManager(vaultManagerAddress);
+
+  const tokenADecimals = (
+    await splToken.getMint(
+      bloomClient.provider.connection,
+      vaultManager.tokenA,
+      "confirmed"
+    )
+  ).decimals;
+
+  await bloomClient.addLiquidity(
+    vaultManagerAddress,
+    new anchor.BN(10 * 10 ** tokenADecimals)
+  );
+
+  while (await bloomClient.isPositionInRange(vaultManagerAddress)) {
+    await testClient.swapAtoB(vaultManagerAddress, 5);
+  }
+}
+
+async function assertFails(promise: Promise<any>) {
+  let failed = false;
+  try {
+    await promise;
+  } catch (_err) {
+    failed = true;
+  }
+  assert.ok(failed);
+}
+
+function sleep(ms: number): Promise<void> {
+  return new Promise((resolve) => setTimeout(resolve, ms));
+}
+
 async function initUserClients(
   connection: anchor.web3.Connection
 ): Promise<[sdk.Client, testSdk.Client]> {